@@ -0,0 +1,258 @@
+use chat_protocol::{decode, encode, ClientMessage, ServerMessage};
+use regex::Regex;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
+
+// How long a read waits before the bot looks for input to post again. A
+// stream handed to `Bot::run` needs a read timeout like this one.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Something said to the bot: in one of its rooms, or as a DM when `room` is None.
+pub struct Message {
+    pub room: Option<String>,
+    pub from: String,
+    pub text: String,
+}
+
+// Answers a message with any number of lines, posted back where it came from.
+pub trait Handler: Send {
+    fn handle(&mut self, message: &Message) -> Vec<String>;
+}
+
+impl<F: FnMut(&Message) -> Vec<String> + Send> Handler for F {
+    fn handle(&mut self, message: &Message) -> Vec<String> {
+        self(message)
+    }
+}
+
+// Replies to messages matching `pattern`. `$1`, `$name` and so on in `reply`
+// are replaced by the pattern's capture groups.
+pub struct Trigger {
+    pub pattern: Regex,
+    pub reply: String,
+}
+
+impl Handler for Trigger {
+    fn handle(&mut self, message: &Message) -> Vec<String> {
+        let Some(captures) = self.pattern.captures(&message.text) else {
+            return Vec::new();
+        };
+        let mut reply = String::new();
+        captures.expand(&self.reply, &mut reply);
+        vec![reply]
+    }
+}
+
+// Runs a shell command for every message, with the text on its stdin and
+// CHAT_ROOM (empty for DMs) and CHAT_FROM set. Each line it prints is a reply.
+// The bot waits for the command, so it should be quick.
+pub struct Exec {
+    pub command: String,
+}
+
+impl Handler for Exec {
+    fn handle(&mut self, message: &Message) -> Vec<String> {
+        match self.run(message) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Command failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Exec {
+    fn run(&self, message: &Message) -> io::Result<Vec<String>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("CHAT_ROOM", message.room.as_deref().unwrap_or(""))
+            .env("CHAT_FROM", &message.from)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // The command may exit without reading its input.
+            let _ = writeln!(stdin, "{}", message.text);
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("`{}` exited with {}", self.command, output.status)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+#[derive(Debug)]
+pub enum BotError {
+    Io(io::Error),
+    // The server refused the login, nick or one of the rooms.
+    Rejected(String),
+    // The server closed the connection before the bot was done.
+    Disconnected,
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Io(e) => write!(f, "{}", e),
+            BotError::Rejected(message) => write!(f, "rejected by server: {}", message),
+            BotError::Disconnected => write!(f, "disconnected by server"),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<io::Error> for BotError {
+    fn from(e: io::Error) -> Self {
+        BotError::Io(e)
+    }
+}
+
+// Opens a plain connection with the read timeout `Bot::run` expects.
+pub fn connect(addr: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(stream)
+}
+
+pub struct Bot {
+    // The nick to take, or the account to log in as with its password.
+    pub nick: Option<String>,
+    pub password: Option<String>,
+    pub rooms: Vec<String>,
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+impl Bot {
+    pub fn new(rooms: Vec<String>) -> Self {
+        Bot { nick: None, password: None, rooms, handlers: Vec::new() }
+    }
+
+    pub fn add_handler(&mut self, handler: impl Handler + 'static) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    // Sets up the nick and rooms, then answers messages in those rooms and
+    // DMs. Lines received on `input` are posted to every room, but only once
+    // the rooms are joined; when `input` is closed the bot quits. Without
+    // `input` it runs until the server closes the connection.
+    pub fn run<S: Read + Write>(&mut self, mut stream: S, input: Option<Receiver<String>>) -> Result<(), BotError> {
+        let mut setup = Vec::new();
+        match (&self.nick, &self.password) {
+            (Some(nick), Some(password)) => {
+                setup.push(ClientMessage::Login { nick: nick.clone(), password: password.clone() })
+            }
+            (Some(nick), None) => setup.push(ClientMessage::Nick { nick: nick.clone() }),
+            _ => {}
+        }
+        setup.extend(self.rooms.iter().map(|room| ClientMessage::Join { room: room.clone() }));
+        for message in &setup {
+            send(&mut stream, message)?;
+        }
+        // Every setup message is answered with an ack or an error, in order.
+        let mut unanswered = setup.len();
+
+        let mut me = String::new();
+        let mut quit = false;
+        let mut pending = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            if unanswered == 0 && !quit {
+                if let Some(input) = &input {
+                    loop {
+                        match input.try_recv() {
+                            Ok(text) => {
+                                for room in &self.rooms {
+                                    send(&mut stream, &ClientMessage::Chat { room: room.clone(), text: text.clone() })?;
+                                }
+                            }
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => {
+                                send(&mut stream, &ClientMessage::Quit)?;
+                                quit = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let n = match stream.read(&mut buffer) {
+                Ok(0) if quit => return Ok(()),
+                Ok(0) => return Err(BotError::Disconnected),
+                Ok(n) => n,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(_) if quit => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            pending.extend_from_slice(&buffer[..n]);
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let message = match decode::<ServerMessage>(&String::from_utf8_lossy(&line)) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("Unreadable message from server: {}", e);
+                        continue;
+                    }
+                };
+                let message = match message {
+                    ServerMessage::Welcome { nick, .. } => {
+                        me = nick;
+                        continue;
+                    }
+                    ServerMessage::Nick { old, new } => {
+                        if old == me {
+                            me = new;
+                        }
+                        continue;
+                    }
+                    ServerMessage::Ping { token } => {
+                        send(&mut stream, &ClientMessage::Pong { token })?;
+                        continue;
+                    }
+                    ServerMessage::Ack { .. } => {
+                        unanswered = unanswered.saturating_sub(1);
+                        continue;
+                    }
+                    ServerMessage::Error { message, .. } if unanswered > 0 => return Err(BotError::Rejected(message)),
+                    ServerMessage::Error { message, .. } => {
+                        eprintln!("Server error: {}", message);
+                        continue;
+                    }
+                    ServerMessage::Chat { room, from, text, .. } if from != me && self.rooms.contains(&room) => {
+                        Message { room: Some(room), from, text }
+                    }
+                    // Bots have no key, so encrypted DMs can't be for them.
+                    ServerMessage::Dm { from, text, encrypted: false, .. } if from != me => Message { room: None, from, text },
+                    _ => continue,
+                };
+
+                for handler in &mut self.handlers {
+                    for text in handler.handle(&message) {
+                        let reply = match &message.room {
+                            Some(room) => ClientMessage::Chat { room: room.clone(), text },
+                            None => ClientMessage::Dm { to: message.from.clone(), text, encrypted: false },
+                        };
+                        send(&mut stream, &reply)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn send<S: Write>(stream: &mut S, message: &ClientMessage) -> io::Result<()> {
+    stream.write_all(encode(message).as_bytes())?;
+    stream.flush()
+}
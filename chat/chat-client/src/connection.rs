@@ -0,0 +1,197 @@
+use crate::tls::{self, Trust};
+use crate::view::Line;
+use crate::{e2e, render, Session};
+use chat_protocol::{decode, encode, ClientMessage, ErrorCode, ServerMessage};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The connection thread alternates between reading with this timeout and
+// sending queued input, since a TLS stream can't be shared between threads.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub trust: Option<Trust>,
+}
+
+impl Endpoint {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+// How a connection ended.
+enum Closed {
+    ByUser,
+    ByServer,
+    // The server's last word was that we are banned, so there's no point in coming back.
+    Banned,
+}
+
+pub fn open(endpoint: &Endpoint) -> io::Result<TcpStream> {
+    let addr = endpoint.addr();
+    let mut error = io::Error::new(ErrorKind::NotFound, format!("cannot resolve {}", addr));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                return Ok(stream);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+fn send<S: Write>(stream: &mut S, message: &ClientMessage, session: &Mutex<Session>) -> io::Result<()> {
+    // Remembered so the login can be repeated after a reconnect, once the server accepted it.
+    if let ClientMessage::Login { nick, password } | ClientMessage::Register { nick, password } = message {
+        session.lock().unwrap().pending_login = Some((nick.clone(), password.clone()));
+    }
+    stream.write_all(encode(message).as_bytes())?;
+    stream.flush()
+}
+
+// Sends `first` and then whatever is queued, and shows what arrives, until
+// either side closes the connection.
+fn pump<S: Read + Write>(
+    stream: &mut S,
+    first: &mut Vec<ClientMessage>,
+    outgoing: &Receiver<ClientMessage>,
+    session: &Mutex<Session>,
+    display: &Sender<Line>,
+) -> io::Result<Closed> {
+    let key = {
+        let mut session = session.lock().unwrap();
+        session.online = true;
+        // Sent before anything else, so the first reply is the one to it.
+        session.publishing_key = true;
+        session.key_published = false;
+        e2e::encode(session.identity.public())
+    };
+    send(stream, &ClientMessage::PublishKey { key }, session)?;
+    let mut quit = false;
+    let mut banned = false;
+    for message in first.drain(..) {
+        quit |= message == ClientMessage::Quit;
+        send(stream, &message, session)?;
+    }
+
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(message) => {
+                    quit |= message == ClientMessage::Quit;
+                    send(stream, &message, session)?;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(Closed::ByUser),
+            }
+        }
+
+        match stream.read(&mut buffer) {
+            Ok(0) if quit => return Ok(Closed::ByUser),
+            Ok(0) if banned => return Ok(Closed::Banned),
+            Ok(0) => return Ok(Closed::ByServer),
+            Ok(n) => {
+                pending.extend_from_slice(&buffer[..n]);
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+                    match decode::<ServerMessage>(&String::from_utf8_lossy(&line)) {
+                        Ok(ServerMessage::Ping { token }) => send(stream, &ClientMessage::Pong { token }, session)?,
+                        Ok(message) => {
+                            banned = matches!(message, ServerMessage::Error { code: ErrorCode::Banned, .. });
+                            for line in render(message, session) {
+                                let _ = display.send(line);
+                            }
+                            let replies = std::mem::take(&mut session.lock().unwrap().replies);
+                            for reply in replies {
+                                send(stream, &reply, session)?;
+                            }
+                        }
+                        Err(e) => {
+                            let _ = display.send(Line::Error(format!("Unreadable message from server: {}", e)));
+                        }
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) if quit => return Ok(Closed::ByUser),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn connect(
+    endpoint: &Endpoint,
+    first: &mut Vec<ClientMessage>,
+    outgoing: &Receiver<ClientMessage>,
+    session: &Mutex<Session>,
+    display: &Sender<Line>,
+) -> io::Result<Closed> {
+    let stream = open(endpoint)?;
+    match &endpoint.trust {
+        Some(trust) => {
+            let mut tls = tls::connect(stream, &endpoint.addr(), &endpoint.host, trust, display)?;
+            let closed = pump(&mut tls, first, outgoing, session, display)?;
+            tls.conn.send_close_notify();
+            let _ = tls.flush();
+            Ok(closed)
+        }
+        None => {
+            let mut stream = stream;
+            pump(&mut stream, first, outgoing, session, display)
+        }
+    }
+}
+
+// Keeps talking to the server until the user quits. A dropped connection is
+// retried with a growing delay, and once it is back the nick, login and rooms
+// are restored.
+pub fn run(
+    endpoint: Endpoint,
+    reconnect: bool,
+    outgoing: Receiver<ClientMessage>,
+    session: &Mutex<Session>,
+    display: Sender<Line>,
+) {
+    // Sent as soon as a connection is up: what restores the session, then
+    // anything typed while there was none.
+    let mut first = Vec::new();
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let result = connect(&endpoint, &mut first, &outgoing, session, &display);
+        let was_online = std::mem::replace(&mut session.lock().unwrap().online, false);
+        let _ = display.send(match &result {
+            Ok(_) => Line::Info("Disconnected from server".to_string()),
+            Err(e) => Line::Info(format!("Connection error: {}", e)),
+        });
+        if matches!(result, Ok(Closed::ByUser | Closed::Banned)) || !reconnect {
+            return;
+        }
+        if was_online {
+            first = session.lock().unwrap().resume();
+            delay = MIN_RECONNECT_DELAY;
+        }
+
+        let _ = display.send(Line::Info(format!("Reconnecting in {}s", delay.as_secs())));
+        let deadline = Instant::now() + delay;
+        loop {
+            match outgoing.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(ClientMessage::Quit) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(message) => first.push(message),
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
@@ -0,0 +1,162 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// A sealed message is base64 of the sender's key, the recipient's key, a
+// random nonce and the ChaCha20-Poly1305 ciphertext. The cipher key is
+// derived from the X25519 shared secret of the two keys, so either of them
+// can open it.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+pub struct Opened {
+    pub sender: PublicKey,
+    pub text: String,
+}
+
+pub fn encode(key: &PublicKey) -> String {
+    BASE64.encode(key.as_bytes())
+}
+
+pub fn decode(key: &str) -> Option<PublicKey> {
+    let bytes: [u8; KEY_LEN] = BASE64.decode(key).ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+// What users read out to each other to check they have the right key.
+pub fn fingerprint(key: &PublicKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Identity {
+    // Loads the key pair from `path`, creating it the first time. Only the
+    // owner may read the file.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        let secret = match fs::read_to_string(path) {
+            Ok(contents) => {
+                let bytes: [u8; KEY_LEN] = BASE64
+                    .decode(contents.trim())
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("{} is not a key", path.display())))?;
+                StaticSecret::from(bytes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+                writeln!(file, "{}", BASE64.encode(secret.to_bytes()))?;
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+        let public = PublicKey::from(&secret);
+        Ok(Identity { secret, public })
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    fn cipher(&self, peer: &PublicKey, sender: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+        let shared = self.secret.diffie_hellman(peer);
+        let mut hasher = Sha256::new();
+        hasher.update(b"chat-e2e-v1");
+        hasher.update(shared.as_bytes());
+        hasher.update(sender.as_bytes());
+        hasher.update(recipient.as_bytes());
+        ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+    }
+
+    pub fn seal(&self, recipient: &PublicKey, text: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(recipient, &self.public, recipient)
+            .encrypt(&nonce, text.as_bytes())
+            .expect("encrypting into a Vec can't fail");
+        let mut sealed = Vec::with_capacity(2 * KEY_LEN + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(self.public.as_bytes());
+        sealed.extend_from_slice(recipient.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        BASE64.encode(sealed)
+    }
+
+    // Opens a message sent to us, or one we sent (as the server echoes it back).
+    pub fn open(&self, sealed: &str) -> Result<Opened, String> {
+        let sealed = BASE64.decode(sealed).map_err(|_| "not a sealed message")?;
+        if sealed.len() < 2 * KEY_LEN + NONCE_LEN {
+            return Err("message too short".to_string());
+        }
+        let (sender, rest) = sealed.split_at(KEY_LEN);
+        let (recipient, rest) = rest.split_at(KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let sender = PublicKey::from(<[u8; KEY_LEN]>::try_from(sender).unwrap());
+        let recipient = PublicKey::from(<[u8; KEY_LEN]>::try_from(recipient).unwrap());
+
+        let peer = if sender == self.public {
+            recipient
+        } else if recipient == self.public {
+            sender
+        } else {
+            return Err("sealed for a different key".to_string());
+        };
+        let text = self
+            .cipher(&peer, &sender, &recipient)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "corrupted or forged")?;
+        let text = String::from_utf8(text).map_err(|_| "not text")?;
+        Ok(Opened { sender, text })
+    }
+}
+
+// The key first seen for each nick, in `nick key` lines. A different key
+// later is only accepted when the user says so.
+pub struct KnownKeys {
+    path: PathBuf,
+    keys: BTreeMap<String, String>,
+}
+
+impl KnownKeys {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let keys = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(nick, key)| (nick.to_string(), key.trim().to_string()))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(KnownKeys { path, keys })
+    }
+
+    pub fn get(&self, nick: &str) -> Option<PublicKey> {
+        self.keys.get(nick).and_then(|key| decode(key))
+    }
+
+    pub fn pin(&mut self, nick: &str, key: &PublicKey) -> io::Result<()> {
+        self.keys.insert(nick.to_string(), encode(key));
+        let contents: String = self.keys.iter().map(|(nick, key)| format!("{} {}\n", nick, key)).collect();
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -0,0 +1,153 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chat_protocol::{ClientMessage, SharedFile};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Raw bytes per upload chunk. Once base64 encoded and wrapped in JSON a chunk
+// stays under the server's default line limit of 4096 bytes.
+const UPLOAD_CHUNK_SIZE: usize = 2048;
+// Chunks sent ahead of the server's acks.
+pub const UPLOAD_WINDOW: usize = 16;
+// Length of the file ids the server hands out, in hex digits.
+const ID_LEN: usize = 16;
+
+// A file being shared. Nothing but the `upload` message is sent until the
+// server accepts it; then chunks are read from the file as earlier ones are
+// acked, hashing it with SHA-256 along the way, and the hash goes last.
+pub struct Upload {
+    file: File,
+    hasher: Sha256,
+    finished: bool,
+}
+
+impl Upload {
+    // Opens the file at `path` and returns it with the message that starts
+    // sharing it in `room`.
+    pub fn open(path: &Path, room: &str) -> io::Result<(Upload, ClientMessage)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not a file"))?
+            .to_string_lossy()
+            .into_owned();
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let upload = Upload { file, hasher: Sha256::new(), finished: false };
+        Ok((upload, ClientMessage::Upload { room: room.to_string(), name, size }))
+    }
+
+    // The next chunk, or the hash once the whole file has been read, or
+    // nothing after that.
+    pub fn next_message(&mut self) -> io::Result<Option<ClientMessage>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut chunk = Vec::with_capacity(UPLOAD_CHUNK_SIZE);
+        (&mut self.file).take(UPLOAD_CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            self.finished = true;
+            let sha256 = hex::encode(std::mem::take(&mut self.hasher).finalize());
+            return Ok(Some(ClientMessage::UploadEnd { sha256 }));
+        }
+        self.hasher.update(&chunk);
+        Ok(Some(ClientMessage::UploadChunk { data: BASE64.encode(&chunk) }))
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+struct Download {
+    file: SharedFile,
+    out: File,
+    part: PathBuf,
+    hasher: Sha256,
+}
+
+// Files being received in answer to /get, written to `<id>.part` until the
+// hash has been checked.
+pub struct Downloads {
+    dir: PathBuf,
+    active: HashMap<String, Download>,
+}
+
+impl Downloads {
+    pub fn new(dir: PathBuf) -> Self {
+        Downloads { dir, active: HashMap::new() }
+    }
+
+    pub fn start(&mut self, file: SharedFile) -> io::Result<()> {
+        // The id names the partial file, so it must not be able to point elsewhere.
+        if file.id.len() != ID_LEN || !file.id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid file id {:?}", file.id)));
+        }
+        fs::create_dir_all(&self.dir)?;
+        let part = self.dir.join(format!("{}.part", file.id));
+        let out = File::create(&part)?;
+        self.active.insert(file.id.clone(), Download { file, out, part, hasher: Sha256::new() });
+        Ok(())
+    }
+
+    pub fn chunk(&mut self, id: &str, data: &str) -> io::Result<()> {
+        let download = match self.active.get_mut(id) {
+            Some(download) => download,
+            None => return Ok(()),
+        };
+        let written = BASE64
+            .decode(data)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+            .and_then(|data| {
+                download.hasher.update(&data);
+                download.out.write_all(&data)
+            });
+        if written.is_err() {
+            self.abort(id);
+        }
+        written
+    }
+
+    // Checks the SHA-256 and moves the file into place, returning where it went.
+    pub fn finish(&mut self, id: &str) -> io::Result<(SharedFile, PathBuf)> {
+        let download = self
+            .active
+            .remove(id)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no such download"))?;
+        let hash = hex::encode(download.hasher.finalize());
+        if hash != download.file.sha256 {
+            let _ = fs::remove_file(&download.part);
+            return Err(io::Error::new(ErrorKind::InvalidData, "SHA-256 mismatch, file discarded"));
+        }
+
+        // Never trust the name to stay inside the download directory.
+        let name = Path::new(&download.file.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| download.file.id.clone());
+        let mut path = self.dir.join(&name);
+        if path.exists() {
+            path = self.dir.join(format!("{}-{}", download.file.id, name));
+        }
+        fs::rename(&download.part, &path)?;
+        Ok((download.file, path))
+    }
+
+    fn abort(&mut self, id: &str) {
+        if let Some(download) = self.active.remove(id) {
+            let _ = fs::remove_file(&download.part);
+        }
+    }
+}
@@ -1,32 +1,794 @@
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+mod connection;
+mod e2e;
+mod files;
+mod screen;
+mod tls;
+mod transcript;
+mod view;
+
+use chat_client::bot::{Bot, BotError, Exec, Trigger};
+use chat_protocol::{ClientMessage, ErrorCode, ServerMessage};
+use chrono::Local;
+use clap::Parser;
+use connection::Endpoint;
+use e2e::{Identity, KnownKeys};
+use files::Downloads;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use tls::Trust;
+use transcript::Transcript;
+use view::{format_time, Line};
+use x25519_dalek::PublicKey;
 
-fn main() {
-    let mut stream = TcpStream::connect("127.0.0.1:8080").expect("Failed to connect to server");
-    let mut stream_clone = stream.try_clone().expect("Failed to clone stream");
+const DEFAULT_HISTORY: usize = 20;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    // Exit when the connection drops instead of reconnecting.
+    #[arg(long)]
+    no_reconnect: bool,
+
+    #[arg(long)]
+    tls: bool,
+
+    // Trust the server's certificate on first use and pin its fingerprint
+    // (for self-signed certificates). Implies --tls.
+    #[arg(long)]
+    tofu: bool,
+
+    // CA or self-signed certificate to verify the server against instead of
+    // the web PKI roots.
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    // Where --tofu pins fingerprints, defaults to ~/.chat_known_servers.
+    #[arg(long)]
+    known_servers: Option<PathBuf>,
+
+    // The key pair for encrypted DMs, created on first use; defaults to
+    // ~/.chat_identity.
+    #[arg(long)]
+    identity: Option<PathBuf>,
+
+    // Where the keys of the people you DM are pinned, defaults to ~/.chat_known_keys.
+    #[arg(long)]
+    known_keys: Option<PathBuf>,
+
+    // Where files fetched with /get are saved.
+    #[arg(long, default_value = "downloads")]
+    downloads: PathBuf,
+
+    // Keep a transcript of each room in <dir>/<room>/<date>.log, searchable
+    // with /search.
+    #[arg(long, value_name = "DIR")]
+    log_dir: Option<PathBuf>,
+
+    // Print messages line by line instead of the full-screen interface, which
+    // is also what happens when stdin or stdout isn't a terminal.
+    #[arg(long)]
+    plain: bool,
+
+    // Run as a bot: join the --join rooms, post each line of stdin to them and
+    // answer messages there (and DMs) with the --reply and --exec handlers.
+    // Exits at the end of stdin.
+    #[arg(long)]
+    bot: bool,
+
+    #[arg(long, value_name = "ROOM", requires = "bot")]
+    join: Vec<String>,
+
+    #[arg(long, requires = "bot")]
+    nick: Option<String>,
+
+    // Log in as --nick with the password in $CHAT_PASSWORD.
+    #[arg(long, requires = "nick")]
+    login: bool,
+
+    // Reply to messages matching a regex; $1 etc. in the reply are its groups.
+    #[arg(long, num_args = 2, value_names = ["PATTERN", "REPLY"], requires = "bot")]
+    reply: Vec<String>,
 
-    thread::spawn(move || {
-        let mut buffer = [0; 512];
-        loop {
-            match stream_clone.read(&mut buffer) {
-                Ok(n) if n == 0 => break,
-                Ok(n) => {
-                    print!("{}", String::from_utf8_lossy(&buffer[..n]));
+    // Run a shell command for each message, with the text on its stdin and
+    // $CHAT_ROOM and $CHAT_FROM set, and post what it prints.
+    #[arg(long, value_name = "COMMAND", requires = "bot")]
+    exec: Vec<String>,
+
+    // Ignore stdin and run until the server closes the connection.
+    #[arg(long, requires = "bot")]
+    listen_only: bool,
+}
+
+struct Session {
+    nick: String,
+    // Still the nick the server made up, so there's none to restore after a reconnect.
+    guest: bool,
+    // The last login the server accepted, and one it hasn't answered yet.
+    login: Option<(String, String)>,
+    pending_login: Option<(String, String)>,
+    // Where plain text goes: the room joined last, or picked with Tab.
+    room: Option<String>,
+    // Members of each room we are in.
+    rooms: BTreeMap<String, BTreeSet<String>>,
+    // Replies still due to the messages restoring the session after a
+    // reconnect; rejoining rooms doesn't change `room` meanwhile.
+    resuming: usize,
+    // False while (re)connecting.
+    online: bool,
+    downloads: Downloads,
+    transcript: Option<Transcript>,
+    // Why logging stopped, until it has been shown.
+    log_failure: Option<io::Error>,
+    identity: Identity,
+    known_keys: KnownKeys,
+    // DMs waiting for the recipient's key before they can be sealed.
+    sealing: HashMap<String, Vec<String>>,
+    // Keys that differ from the pinned ones, until accepted with /trust.
+    changed_keys: HashMap<String, PublicKey>,
+    // Messages to send in response to something the server sent.
+    replies: Vec<ClientMessage>,
+    // The file being shared with /send, sent a chunk at a time as the server acks them.
+    upload: Option<files::Upload>,
+    // The key sent on connecting hasn't been answered yet. A server that
+    // requires logging in first refuses it, and then it is published again
+    // once logged in.
+    publishing_key: bool,
+    key_published: bool,
+}
+
+// What a line of input turns into: messages for the server, or output of a
+// command that is handled locally.
+enum Input {
+    Send(Vec<ClientMessage>),
+    Show(Vec<Line>),
+}
+
+impl Session {
+    fn new(downloads: Downloads, transcript: Option<Transcript>, identity: Identity, known_keys: KnownKeys) -> Self {
+        Session {
+            nick: String::new(),
+            guest: true,
+            login: None,
+            pending_login: None,
+            room: None,
+            rooms: BTreeMap::new(),
+            resuming: 0,
+            online: false,
+            downloads,
+            transcript,
+            log_failure: None,
+            identity,
+            known_keys,
+            sealing: HashMap::new(),
+            changed_keys: HashMap::new(),
+            replies: Vec::new(),
+            upload: None,
+            publishing_key: false,
+            key_published: false,
+        }
+    }
+
+    // Queues up to `count` more messages of the upload in progress.
+    fn continue_upload(&mut self, count: usize) -> Vec<Line> {
+        let Some(upload) = &mut self.upload else {
+            return Vec::new();
+        };
+        for _ in 0..count {
+            match upload.next_message() {
+                Ok(Some(message)) => self.replies.push(message),
+                Ok(None) => break,
+                Err(e) => {
+                    self.upload = None;
+                    return vec![Line::Error(format!("Upload failed: {}", e))];
                 }
-                Err(_) => break,
             }
         }
-    });
+        Vec::new()
+    }
 
+    fn pin(&mut self, nick: &str, key: &PublicKey) -> Line {
+        match self.known_keys.pin(nick, key) {
+            Ok(()) => Line::Info(format!(
+                "Pinned {}'s key, fingerprint {}; check it with them (/fingerprint {})",
+                nick,
+                e2e::fingerprint(key),
+                nick
+            )),
+            Err(e) => Line::Error(format!("Cannot save {}'s key: {}", nick, e)),
+        }
+    }
+
+    // The recipient's key arrived: seal the DMs waiting for it, unless it
+    // isn't the key we know them by.
+    fn key_arrived(&mut self, nick: String, key: Option<String>) -> Vec<Line> {
+        let texts = self.sealing.remove(&nick).unwrap_or_default();
+        if texts.is_empty() {
+            return Vec::new();
+        }
+        let pinned = self.known_keys.get(&nick);
+        let Some(key) = key.as_deref().and_then(e2e::decode) else {
+            if pinned.is_some() {
+                return vec![Line::Error(format!(
+                    "{} used to have an encryption key but has none now, message not sent",
+                    nick
+                ))];
+            }
+            return vec![Line::Error(format!(
+                "{} can't receive encrypted messages, message not sent. Use /msg-plain {} <text> to send it unencrypted",
+                nick, nick
+            ))];
+        };
+
+        let mut lines = Vec::new();
+        match pinned {
+            Some(pinned) if pinned != key => {
+                let line = Line::Error(format!(
+                    "{}'s key has changed (fingerprint now {}), message not sent. If they confirm it, accept it with /trust {}",
+                    nick,
+                    e2e::fingerprint(&key),
+                    nick
+                ));
+                self.changed_keys.insert(nick, key);
+                return vec![line];
+            }
+            Some(_) => {}
+            None => lines.push(self.pin(&nick, &key)),
+        }
+        for text in texts {
+            let text = self.identity.seal(&key, &text);
+            self.replies.push(ClientMessage::Dm { to: nick.clone(), text, encrypted: true });
+        }
+        lines
+    }
+
+    fn open_dm(&mut self, from: String, to: String, sealed: &str, timestamp: i64) -> Vec<Line> {
+        let opened = match self.identity.open(sealed) {
+            Ok(opened) => opened,
+            Err(e) => return vec![Line::Error(format!("Cannot decrypt a message from {}: {}", from, e))],
+        };
+        let mut lines = Vec::new();
+        // Our own messages come back too; only others' keys need checking.
+        if opened.sender != *self.identity.public() {
+            match self.known_keys.get(&from) {
+                None => lines.push(self.pin(&from, &opened.sender)),
+                Some(pinned) if pinned != opened.sender => {
+                    lines.push(Line::Error(format!(
+                        "The next message claims to be from {} but uses a different key (fingerprint {}). \
+                         If they confirm it, accept it with /trust {}",
+                        from,
+                        e2e::fingerprint(&opened.sender),
+                        from
+                    )));
+                    self.changed_keys.insert(from.clone(), opened.sender);
+                }
+                Some(_) => {}
+            }
+        }
+        lines.push(Line::Dm { time: format_time(timestamp), from, to, text: opened.text, encrypted: true });
+        lines
+    }
+
+    // Logging stops at the first failure instead of failing for every message.
+    fn log(&mut self, room: &str, timestamp: i64, text: &str) {
+        if let Some(transcript) = &self.transcript {
+            if let Err(e) = transcript.record(room, timestamp, text) {
+                self.transcript = None;
+                self.log_failure = Some(e);
+            }
+        }
+    }
+
+    // What gets a new connection back to where the last one was: the same
+    // account or nick, and the same rooms.
+    fn resume(&mut self) -> Vec<ClientMessage> {
+        let mut messages = Vec::new();
+        match &self.login {
+            Some((nick, password)) => messages.push(ClientMessage::Login { nick: nick.clone(), password: password.clone() }),
+            None if !self.guest => messages.push(ClientMessage::Nick { nick: self.nick.clone() }),
+            None => {}
+        }
+        let rooms = std::mem::take(&mut self.rooms);
+        messages.extend(rooms.into_keys().map(|room| ClientMessage::Join { room }));
+        // The server forgot about it along with the old connection.
+        self.upload = None;
+        self.resuming = messages.len();
+        messages
+    }
+}
+
+fn render(message: ServerMessage, session: &Mutex<Session>) -> Vec<Line> {
+    let mut session = session.lock().unwrap();
+    let line = match message {
+        ServerMessage::Welcome { id, nick } => {
+            let line = Line::Info(format!("Connected as {} (#{})", nick, id));
+            session.nick = nick;
+            session.guest = true;
+            line
+        }
+        ServerMessage::Chat { room, from, text, timestamp } => {
+            session.log(&room, timestamp, &format!("<{}> {}", from, text));
+            Line::Chat { time: format_time(timestamp), room: Some(room), from, text }
+        }
+        ServerMessage::Join { room, nick } => {
+            if nick == session.nick && (session.resuming == 0 || session.room.is_none()) {
+                session.room = Some(room.clone());
+            }
+            session.rooms.entry(room.clone()).or_default().insert(nick.clone());
+            session.log(&room, Local::now().timestamp(), &format!("* {} joined", nick));
+            Line::Event(format!("{} joined #{}", nick, room))
+        }
+        ServerMessage::Leave { room, nick } => {
+            if nick == session.nick {
+                session.rooms.remove(&room);
+                if session.room.as_deref() == Some(room.as_str()) {
+                    session.room = session.rooms.keys().next().cloned();
+                }
+            } else if let Some(members) = session.rooms.get_mut(&room) {
+                members.remove(&nick);
+            }
+            session.log(&room, Local::now().timestamp(), &format!("* {} left", nick));
+            Line::Event(format!("{} left #{}", nick, room))
+        }
+        ServerMessage::Nick { old, new } => {
+            if old == session.nick {
+                session.nick = new.clone();
+                session.guest = false;
+            }
+            for members in session.rooms.values_mut() {
+                if members.remove(&old) {
+                    members.insert(new.clone());
+                }
+            }
+            Line::Event(format!("{} is now known as {}", old, new))
+        }
+        ServerMessage::Dm { from, to, text, timestamp, encrypted: true } => return session.open_dm(from, to, &text, timestamp),
+        ServerMessage::Dm { from, to, text, timestamp, encrypted: false } => {
+            Line::Dm { time: format_time(timestamp), from, to, text, encrypted: false }
+        }
+        ServerMessage::Key { nick, key } => return session.key_arrived(nick, key),
+        ServerMessage::History { room, messages } => {
+            if messages.is_empty() {
+                return vec![Line::Marker(format!("No history in #{}", room))];
+            }
+            let mut lines = vec![Line::Marker(format!("Last {} messages in #{}", messages.len(), room))];
+            lines.extend(messages.into_iter().map(|entry| Line::Chat {
+                time: format_time(entry.timestamp),
+                room: None,
+                from: entry.from,
+                text: entry.text,
+            }));
+            lines.push(Line::Marker("End of history".to_string()));
+            return lines;
+        }
+        ServerMessage::Members { room, nicks } => {
+            let line = Line::Info(format!("In #{}: {}", room, nicks.join(", ")));
+            session.rooms.insert(room, nicks.into_iter().collect());
+            line
+        }
+        ServerMessage::System { text } => Line::Info(text),
+        ServerMessage::Error { code: ErrorCode::Unauthenticated, .. } if session.publishing_key => {
+            session.publishing_key = false;
+            return Vec::new();
+        }
+        ServerMessage::Error { code, message } => {
+            session.publishing_key = false;
+            session.resuming = session.resuming.saturating_sub(1);
+            // The server refused the upload or gave up on it, so stop sending chunks.
+            if matches!(
+                code,
+                ErrorCode::FileTooLarge
+                    | ErrorCode::TransferFailed
+                    | ErrorCode::Internal
+                    | ErrorCode::InvalidMessage
+                    | ErrorCode::NotInRoom
+                    | ErrorCode::Muted
+            ) {
+                session.upload = None;
+            }
+            Line::Error(message)
+        }
+        ServerMessage::File { room, from, file, timestamp } => {
+            let text = format!(
+                "shared {} ({}, SHA-256 {}), fetch it with /get {}",
+                file.name,
+                files::format_size(file.size),
+                file.sha256,
+                file.id
+            );
+            session.log(&room, timestamp, &format!("* {} {}", from, text));
+            Line::Action { time: format_time(timestamp), room, from, text }
+        }
+        ServerMessage::FileStart { file } => {
+            let line = Line::Info(format!("Downloading {} ({})", file.name, files::format_size(file.size)));
+            if let Err(e) = session.downloads.start(file) {
+                return vec![line, Line::Error(format!("Cannot save download: {}", e))];
+            }
+            line
+        }
+        ServerMessage::FileChunk { id, data } => match session.downloads.chunk(&id, &data) {
+            Ok(()) => return Vec::new(),
+            Err(e) => Line::Error(format!("Download failed: {}", e)),
+        },
+        ServerMessage::FileEnd { id } => match session.downloads.finish(&id) {
+            Ok((file, path)) => Line::Info(format!("Saved {} to {} (SHA-256 verified)", file.name, path.display())),
+            Err(e) => Line::Error(format!("Download failed: {}", e)),
+        },
+        // Not part of restoring the session, so it doesn't count against `resuming`.
+        ServerMessage::Ack { command } if command == "publish_key" => {
+            session.publishing_key = false;
+            session.key_published = true;
+            return Vec::new();
+        }
+        ServerMessage::Ack { command } => {
+            session.resuming = session.resuming.saturating_sub(1);
+            if command == "login" || command == "register" {
+                if let Some(login) = session.pending_login.take() {
+                    session.login = Some(login);
+                }
+                if !session.key_published {
+                    let key = e2e::encode(session.identity.public());
+                    session.replies.push(ClientMessage::PublishKey { key });
+                }
+            }
+            return match command.as_str() {
+                "upload" => session.continue_upload(files::UPLOAD_WINDOW),
+                "upload_chunk" => session.continue_upload(1),
+                "upload_end" => {
+                    session.upload = None;
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            };
+        }
+        ServerMessage::Ping { .. } => return Vec::new(),
+    };
+    let mut lines = vec![line];
+    if let Some(e) = session.log_failure.take() {
+        lines.push(Line::Error(format!("Transcript logging stopped: {}", e)));
+    }
+    lines
+}
+
+// Accepts plain seconds or a number with an s/m/h/d suffix, e.g. "90", "10m".
+fn parse_duration(text: &str) -> Option<u64> {
+    let (number, unit) = match text.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&text[..i], c),
+        _ => (text, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn split_reason(arg: &str) -> (String, Option<String>) {
+    match arg.split_once(' ') {
+        Some((target, reason)) => (target.to_string(), Some(reason.trim().to_string())),
+        None => (arg.to_string(), None),
+    }
+}
+
+fn parse_input(input: &str, session: &Mutex<Session>) -> Result<ClientMessage, String> {
+    let room = session.lock().unwrap().room.clone();
+
+    if !input.starts_with('/') {
+        let room = room.ok_or("You are not in a room, use /join <room>")?;
+        return Ok(ClientMessage::Chat { room, text: input.to_string() });
+    }
+
+    let mut parts = input.splitn(2, ' ');
+    let command = parts.next().unwrap_or_default();
+    let arg = parts.next().unwrap_or_default().trim();
+
+    match command {
+        "/join" if !arg.is_empty() => Ok(ClientMessage::Join { room: arg.trim_start_matches('#').to_string() }),
+        "/leave" => {
+            let room = if arg.is_empty() { room } else { Some(arg.trim_start_matches('#').to_string()) };
+            Ok(ClientMessage::Leave { room: room.ok_or("Usage: /leave <room>")? })
+        }
+        "/nick" if !arg.is_empty() => Ok(ClientMessage::Nick { nick: arg.to_string() }),
+        "/msg" => {
+            let (to, text) = arg.split_once(' ').ok_or("Usage: /msg <nick> <text>")?;
+            // Sent once the server tells us the recipient's key.
+            let mut session = session.lock().unwrap();
+            session.sealing.entry(to.to_string()).or_default().push(text.trim().to_string());
+            Ok(ClientMessage::GetKey { nick: to.to_string() })
+        }
+        "/msg-plain" => {
+            let (to, text) = arg.split_once(' ').ok_or("Usage: /msg-plain <nick> <text>")?;
+            Ok(ClientMessage::Dm { to: to.to_string(), text: text.trim().to_string(), encrypted: false })
+        }
+        "/history" => {
+            let limit = if arg.is_empty() {
+                DEFAULT_HISTORY
+            } else {
+                arg.parse().map_err(|_| "Usage: /history <n>")?
+            };
+            let room = room.ok_or("You are not in a room, use /join <room>")?;
+            Ok(ClientMessage::History { room, limit })
+        }
+        "/register" | "/login" => {
+            let (nick, password) = arg
+                .split_once(' ')
+                .ok_or_else(|| format!("Usage: {} <nick> <password>", command))?;
+            let (nick, password) = (nick.to_string(), password.trim().to_string());
+            if command == "/register" {
+                Ok(ClientMessage::Register { nick, password })
+            } else {
+                Ok(ClientMessage::Login { nick, password })
+            }
+        }
+        "/kick" if !arg.is_empty() => {
+            let (nick, reason) = split_reason(arg);
+            Ok(ClientMessage::Kick { nick, reason })
+        }
+        "/ban" if !arg.is_empty() => {
+            let (target, reason) = split_reason(arg);
+            Ok(ClientMessage::Ban { target, reason })
+        }
+        "/unban" if !arg.is_empty() => Ok(ClientMessage::Unban { target: arg.to_string() }),
+        "/mute" => {
+            let (nick, duration) = arg.split_once(' ').ok_or("Usage: /mute <nick> <duration, e.g. 30s, 10m, 1h>")?;
+            let seconds = parse_duration(duration.trim()).ok_or("Usage: /mute <nick> <duration, e.g. 30s, 10m, 1h>")?;
+            Ok(ClientMessage::Mute { nick: nick.to_string(), seconds })
+        }
+        "/unmute" if !arg.is_empty() => Ok(ClientMessage::Unmute { nick: arg.to_string() }),
+        "/op" if !arg.is_empty() => Ok(ClientMessage::Op { nick: arg.to_string() }),
+        "/deop" if !arg.is_empty() => Ok(ClientMessage::Deop { nick: arg.to_string() }),
+        "/get" if !arg.is_empty() => Ok(ClientMessage::Download { id: arg.to_string() }),
+        "/quit" => Ok(ClientMessage::Quit),
+        _ => Err("Commands: /join <room>, /leave [room], /nick <name>, /msg <nick> <text>, /msg-plain <nick> <text>, /history [n], \
+                  /send <file>, /get <id>, /search <text>, /fingerprint [nick], /trust <nick>, /register <nick> <password>, /login <nick> <password>, /quit\n  \
+                  Operators: /kick <nick> [reason], /ban <nick|ip> [reason], /unban <nick|ip>, \
+                  /mute <nick> <duration>, /unmute <nick>, /op <account>, /deop <account>"
+            .to_string()),
+    }
+}
+
+// Like parse_input, for commands that turn into several messages.
+fn parse_line(input: &str, session: &Mutex<Session>) -> Result<Input, String> {
+    let arg = |command: &str| match input.strip_prefix(command) {
+        Some(arg) if arg.is_empty() || arg.starts_with(' ') => Some(arg.trim()),
+        _ => None,
+    };
+    if let Some(path) = arg("/send") {
+        parse_send(path, session).map(Input::Send)
+    } else if let Some(query) = arg("/search") {
+        search(query, session).map(Input::Show)
+    } else if let Some(nick) = arg("/fingerprint") {
+        Ok(Input::Show(vec![fingerprint(nick, &session.lock().unwrap())]))
+    } else if let Some(nick) = arg("/trust") {
+        trust_key(nick, &mut session.lock().unwrap()).map(|line| Input::Show(vec![line]))
+    } else {
+        parse_input(input, session).map(|message| Input::Send(vec![message]))
+    }
+}
+
+fn fingerprint(nick: &str, session: &Session) -> Line {
+    if nick.is_empty() {
+        return Line::Info(format!("Your key fingerprint is {}", e2e::fingerprint(session.identity.public())));
+    }
+    match session.known_keys.get(nick) {
+        Some(key) => Line::Info(format!("{}'s key fingerprint is {}", nick, e2e::fingerprint(&key))),
+        None => Line::Info(format!("No key pinned for {} yet, it is pinned with the first encrypted DM", nick)),
+    }
+}
+
+fn trust_key(nick: &str, session: &mut Session) -> Result<Line, String> {
+    if nick.is_empty() {
+        return Err("Usage: /trust <nick>".to_string());
+    }
+    let key = session
+        .changed_keys
+        .remove(nick)
+        .ok_or_else(|| format!("{}'s key hasn't changed", nick))?;
+    Ok(session.pin(nick, &key))
+}
+
+fn search(query: &str, session: &Mutex<Session>) -> Result<Vec<Line>, String> {
+    if query.is_empty() {
+        return Err("Usage: /search <text>".to_string());
+    }
+    // Searching can take a while, so not while holding the session.
+    let transcript = session.lock().unwrap().transcript.clone();
+    let transcript = transcript.ok_or("Transcript logging is off, start the client with --log-dir <dir>")?;
+    let results = transcript.search(query).map_err(|e| format!("Search failed: {}", e))?;
+    if results.excerpts.is_empty() {
+        return Ok(vec![Line::Info(format!("No matches for '{}'", query))]);
+    }
+
+    let mut lines = vec![Line::Marker(format!("Matches for '{}'", query))];
+    if results.omitted > 0 {
+        lines.push(Line::Info(format!("{} earlier excerpts not shown", results.omitted)));
+    }
+    for excerpt in results.excerpts {
+        lines.push(Line::Marker(format!("#{} {}", excerpt.room, excerpt.date)));
+        lines.extend(excerpt.lines.into_iter().map(|(text, hit)| Line::Found { text, hit }));
+    }
+    lines.push(Line::Marker("End of search".to_string()));
+    Ok(lines)
+}
+
+// `/send` starts an upload; its chunks and the hash follow as the server acks them.
+fn parse_send(path: &str, session: &Mutex<Session>) -> Result<Vec<ClientMessage>, String> {
+    if path.is_empty() {
+        return Err("Usage: /send <file>".to_string());
+    }
+    let mut session = session.lock().unwrap();
+    let room = session.room.clone().ok_or("You are not in a room, use /join <room>")?;
+    let (upload, message) = files::Upload::open(Path::new(path), &room).map_err(|e| format!("Cannot send {}: {}", path, e))?;
+    // Replaces an unfinished upload, as the server does.
+    session.upload = Some(upload);
+    Ok(vec![message])
+}
+
+fn home_file(name: &str) -> PathBuf {
+    env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(name)
+}
+
+fn trust(cli: &Cli) -> Option<Trust> {
+    if cli.tofu {
+        let known_servers = cli.known_servers.clone().unwrap_or_else(|| home_file(".chat_known_servers"));
+        Some(Trust::FirstUse { known_servers })
+    } else if cli.tls || cli.ca_cert.is_some() {
+        Some(Trust::Roots(cli.ca_cert.clone()))
+    } else {
+        None
+    }
+}
+
+// Line mode: reads commands from stdin while another thread prints.
+fn read_input(session: &Mutex<Session>, outgoing: &Sender<ClientMessage>) {
     let mut input = String::new();
     loop {
         input.clear();
-        if io::stdin().read_line(&mut input).is_err() {
-            break;
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
         }
-        if stream.write_all(input.as_bytes()).is_err() {
-            break;
+
+        match parse_line(input, session) {
+            Ok(Input::Send(messages)) => {
+                if messages.into_iter().any(|message| outgoing.send(message).is_err()) {
+                    break;
+                }
+            }
+            Ok(Input::Show(lines)) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Err(usage) => println!("{}", Line::Error(usage)),
         }
     }
-}
\ No newline at end of file
+}
+
+fn bot(cli: &Cli) -> Result<Bot, String> {
+    if cli.join.is_empty() {
+        return Err("--bot needs at least one --join".to_string());
+    }
+    let mut bot = Bot::new(cli.join.clone());
+    bot.nick = cli.nick.clone();
+    if cli.login {
+        bot.password = Some(env::var("CHAT_PASSWORD").map_err(|_| "--login needs $CHAT_PASSWORD".to_string())?);
+    }
+    for pair in cli.reply.chunks(2) {
+        let pattern = Regex::new(&pair[0]).map_err(|e| format!("Invalid --reply pattern: {}", e))?;
+        bot.add_handler(Trigger { pattern, reply: pair[1].clone() });
+    }
+    for command in &cli.exec {
+        bot.add_handler(Exec { command: command.clone() });
+    }
+    Ok(bot)
+}
+
+fn run_bot(mut bot: Bot, endpoint: &Endpoint, listen_only: bool) -> Result<(), BotError> {
+    let input = if listen_only {
+        None
+    } else {
+        let (lines, input) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if !line.trim().is_empty() && lines.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Some(input)
+    };
+
+    let stream = connection::open(endpoint)?;
+    match &endpoint.trust {
+        Some(trust) => {
+            let (display, notices) = mpsc::channel();
+            let tls = tls::connect(stream, &endpoint.addr(), &endpoint.host, trust, &display);
+            for notice in notices.try_iter() {
+                eprintln!("{}", notice);
+            }
+            bot.run(tls?, input)
+        }
+        None => bot.run(stream, input),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let endpoint = Endpoint { host: cli.host.clone(), port: cli.port, trust: trust(&cli) };
+    if cli.bot {
+        let result = bot(&cli).and_then(|bot| run_bot(bot, &endpoint, cli.listen_only).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let reconnect = !cli.no_reconnect;
+    let identity_path = cli.identity.clone().unwrap_or_else(|| home_file(".chat_identity"));
+    let identity = Identity::load_or_create(&identity_path).unwrap_or_else(|e| {
+        eprintln!("Cannot load {}: {}", identity_path.display(), e);
+        process::exit(1);
+    });
+    let known_keys_path = cli.known_keys.clone().unwrap_or_else(|| home_file(".chat_known_keys"));
+    let known_keys = KnownKeys::load(known_keys_path.clone()).unwrap_or_else(|e| {
+        eprintln!("Cannot load {}: {}", known_keys_path.display(), e);
+        process::exit(1);
+    });
+    let session = Arc::new(Mutex::new(Session::new(
+        Downloads::new(cli.downloads.clone()),
+        cli.log_dir.clone().map(Transcript::new),
+        identity,
+        known_keys,
+    )));
+
+    let (outgoing, queued) = mpsc::channel();
+    let (display, lines) = mpsc::channel();
+    let connection_session = Arc::clone(&session);
+    let connection = thread::spawn(move || {
+        connection::run(endpoint, reconnect, queued, &connection_session, display);
+    });
+
+    if !cli.plain && io::stdin().is_terminal() && io::stdout().is_terminal() {
+        if let Err(e) = screen::run(&session, &outgoing, lines) {
+            eprintln!("Terminal error: {}", e);
+        }
+        drop(outgoing);
+        let _ = connection.join();
+        return;
+    }
+
+    // The printer exits once the connection is gone for good, even while
+    // stdin is still open.
+    let printer = thread::spawn(move || {
+        for line in lines {
+            println!("{}", line);
+        }
+        process::exit(0);
+    });
+    read_input(&session, &outgoing);
+
+    // Let the connection thread send whatever is still queued before exiting.
+    drop(outgoing);
+    let _ = connection.join();
+    let _ = printer.join();
+}
@@ -0,0 +1,460 @@
+use crate::view::Line;
+use crate::{Input, Session};
+use chat_protocol::ClientMessage;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::time::Duration;
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use tui::{Frame, Terminal};
+use unicode_width::UnicodeWidthChar;
+
+// How long to wait for a key press before checking for new messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_SCROLLBACK: usize = 5000;
+const MAX_INPUT_HISTORY: usize = 100;
+const SIDEBAR_WIDTH: u16 = 24;
+const NICK_COLORS: [Color; 10] = [
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightMagenta,
+    Color::LightCyan,
+];
+
+// Puts the terminal back however the UI exits, including by a panic.
+struct Restore;
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+fn nick_style(nick: &str, me: &str) -> Style {
+    let hash = nick.bytes().fold(0usize, |hash, b| hash.wrapping_mul(31).wrapping_add(b as usize));
+    let style = Style::default().fg(NICK_COLORS[hash % NICK_COLORS.len()]);
+    if nick == me {
+        style.add_modifier(Modifier::BOLD)
+    } else {
+        style
+    }
+}
+
+fn spans(line: &Line, me: &str) -> Vec<Span<'static>> {
+    let time = |time: &str| Span::styled(format!("[{}] ", time), Style::default().fg(Color::DarkGray));
+    let room = |room: &str| Span::styled(format!("#{} ", room), Style::default().fg(Color::Blue));
+    let nick = |nick: &str| Span::styled(nick.to_string(), nick_style(nick, me));
+    match line {
+        Line::Chat { time: t, room: r, from, text } => {
+            let mut spans = vec![time(t)];
+            spans.extend(r.as_deref().map(room));
+            spans.extend([Span::raw("<"), nick(from), Span::raw("> "), Span::raw(text.clone())]);
+            spans
+        }
+        Line::Dm { time: t, from, to, text, encrypted } => {
+            let dm = Style::default().fg(Color::Magenta);
+            let mut spans = vec![
+                time(t),
+                Span::styled("[", dm),
+                nick(from),
+                Span::styled(" -> ", dm),
+                nick(to),
+                Span::styled("] ", dm),
+                Span::styled(text.clone(), dm),
+            ];
+            if !encrypted {
+                spans.push(Span::styled(" (unencrypted)", Style::default().fg(Color::DarkGray)));
+            }
+            spans
+        }
+        Line::Action { time: t, room: r, from, text } => vec![
+            time(t),
+            room(r),
+            Span::raw("* "),
+            nick(from),
+            Span::styled(format!(" {}", text), Style::default().add_modifier(Modifier::ITALIC)),
+        ],
+        Line::Event(_) => vec![Span::styled(line.to_string(), Style::default().fg(Color::Green))],
+        Line::Info(_) => vec![Span::styled(line.to_string(), Style::default().fg(Color::Yellow))],
+        Line::Marker(_) => vec![Span::styled(line.to_string(), Style::default().fg(Color::DarkGray))],
+        Line::Error(_) => vec![Span::styled(line.to_string(), Style::default().fg(Color::Red))],
+        Line::Found { text, hit: true } => vec![Span::styled(text.clone(), Style::default().add_modifier(Modifier::BOLD))],
+        Line::Found { text, hit: false } => vec![Span::styled(text.clone(), Style::default().fg(Color::DarkGray))],
+    }
+}
+
+fn to_spans(cells: Vec<(char, Style)>) -> Spans<'static> {
+    let mut spans: Vec<Span> = Vec::new();
+    for (c, style) in cells {
+        match spans.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push(c),
+            _ => spans.push(Span::styled(c.to_string(), style)),
+        }
+    }
+    Spans::from(spans)
+}
+
+// Breaks styled text into rows of at most `width` columns, at spaces where possible.
+fn wrap(spans: Vec<Span<'static>>, width: usize) -> Vec<Spans<'static>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row: Vec<(char, Style)> = Vec::new();
+    let mut used = 0;
+    for span in spans {
+        for c in span.content.chars() {
+            if c == '\n' {
+                rows.push(to_spans(std::mem::take(&mut row)));
+                used = 0;
+                continue;
+            }
+            let c_width = c.width().unwrap_or(0);
+            if used + c_width > width {
+                let rest = match row.iter().rposition(|&(c, _)| c == ' ') {
+                    Some(space) if space > 0 => {
+                        let rest = row.split_off(space + 1);
+                        row.pop();
+                        rest
+                    }
+                    _ => Vec::new(),
+                };
+                rows.push(to_spans(std::mem::replace(&mut row, rest)));
+                used = row.iter().map(|&(c, _)| c.width().unwrap_or(0)).sum();
+            }
+            row.push((c, span.style));
+            used += c_width;
+        }
+    }
+    rows.push(to_spans(row));
+    rows
+}
+
+struct Screen {
+    lines: VecDeque<Line>,
+    // Rows scrolled up from the newest message.
+    scroll: usize,
+    // Size of the message pane at the last draw.
+    width: usize,
+    height: usize,
+    me: String,
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    // Position in `history` while browsing it with Up and Down, and what was
+    // typed before browsing started.
+    browsing: Option<usize>,
+    draft: Vec<char>,
+    connected: bool,
+    quitting: bool,
+}
+
+impl Screen {
+    fn new() -> Self {
+        Screen {
+            lines: VecDeque::new(),
+            scroll: 0,
+            width: 80,
+            height: 20,
+            me: String::new(),
+            input: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            draft: Vec::new(),
+            connected: true,
+            quitting: false,
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        // Keep the view still while scrolled back.
+        if self.scroll > 0 {
+            self.scroll += wrap(spans(&line, &self.me), self.width).len();
+        }
+        self.lines.push_back(line);
+        if self.lines.len() > MAX_SCROLLBACK {
+            self.lines.pop_front();
+        }
+    }
+
+    fn set_input(&mut self, input: Vec<char>) {
+        self.cursor = input.len();
+        self.input = input;
+    }
+
+    fn previous_input(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.input.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.set_input(self.history[index].chars().collect());
+    }
+
+    fn next_input(&mut self) {
+        let index = match self.browsing {
+            Some(index) => index + 1,
+            None => return,
+        };
+        if index < self.history.len() {
+            self.browsing = Some(index);
+            self.set_input(self.history[index].chars().collect());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set_input(draft);
+        }
+    }
+
+    // Returns false once the UI should exit.
+    fn key(&mut self, key: KeyEvent, session: &Mutex<Session>, outgoing: &Sender<ClientMessage>) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let page = self.height.saturating_sub(1).max(1);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return false,
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => return false,
+            KeyCode::Char('u') if ctrl => self.set_input(Vec::new()),
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.previous_input(),
+            KeyCode::Down => self.next_input(),
+            KeyCode::PageUp => self.scroll += page,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(page),
+            KeyCode::Tab => switch_room(session, true),
+            KeyCode::BackTab => switch_room(session, false),
+            KeyCode::Enter => return self.submit(session, outgoing),
+            _ => {}
+        }
+        true
+    }
+
+    fn submit(&mut self, session: &Mutex<Session>, outgoing: &Sender<ClientMessage>) -> bool {
+        let input: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        self.browsing = None;
+        let input = input.trim();
+        if input.is_empty() {
+            return true;
+        }
+        if self.history.last().map(String::as_str) != Some(input) {
+            self.history.push(input.to_string());
+            if self.history.len() > MAX_INPUT_HISTORY {
+                self.history.remove(0);
+            }
+        }
+        self.scroll = 0;
+
+        let messages = match crate::parse_line(input, session) {
+            Ok(Input::Send(messages)) => messages,
+            Ok(Input::Show(lines)) => {
+                for line in lines {
+                    self.push(line);
+                }
+                return true;
+            }
+            Err(usage) => {
+                self.push(Line::Error(usage));
+                return true;
+            }
+        };
+        let quit = messages.contains(&ClientMessage::Quit);
+        if !self.connected {
+            if !quit {
+                self.push(Line::Error("Not connected, /quit to exit".to_string()));
+            }
+            return !quit;
+        }
+        for message in messages {
+            if outgoing.send(message).is_err() {
+                break;
+            }
+        }
+        // Exit once the server has closed the connection.
+        self.quitting |= quit;
+        true
+    }
+
+    fn draw<B: Backend>(&mut self, frame: &mut Frame<B>, session: &Session) {
+        self.me = session.nick.clone();
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+            .split(rows[0]);
+        self.draw_messages(frame, columns[0]);
+        draw_sidebar(frame, columns[1], session);
+        self.draw_input(frame, rows[1], session);
+    }
+
+    fn draw_messages<B: Backend>(&mut self, frame: &mut Frame<B>, area: Rect) {
+        let title = if self.scroll > 0 {
+            " Messages (scrolled back, PgDn to return) "
+        } else {
+            " Messages (PgUp/PgDn scroll, Tab switch room, Ctrl-C quit) "
+        };
+        let block = Block::default().borders(Borders::ALL).title(title);
+        let inner = block.inner(area);
+        self.width = inner.width as usize;
+        self.height = inner.height as usize;
+
+        // Only wrap as many of the newest lines as can be seen.
+        let wanted = self.height + self.scroll;
+        let mut rows = VecDeque::new();
+        for line in self.lines.iter().rev() {
+            if rows.len() >= wanted {
+                break;
+            }
+            for row in wrap(spans(line, &self.me), self.width).into_iter().rev() {
+                rows.push_front(row);
+            }
+        }
+        self.scroll = self.scroll.min(rows.len().saturating_sub(self.height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(self.height);
+        let visible: Vec<Spans> = rows.into_iter().skip(start).take(end - start).collect();
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+    }
+
+    fn draw_input<B: Backend>(&mut self, frame: &mut Frame<B>, area: Rect, session: &Session) {
+        let title = match &session.room {
+            _ if !self.connected => " Disconnected, /quit to exit ".to_string(),
+            _ if !session.online => " Connecting... ".to_string(),
+            Some(room) => format!(" #{} as {} ", room, session.nick),
+            None => format!(" {} ", session.nick),
+        };
+        let block = Block::default().borders(Borders::ALL).title(title);
+        let inner = block.inner(area);
+
+        // Scroll long input sideways to keep the cursor in view.
+        let width = (inner.width as usize).max(1);
+        let mut offset: usize = self.input[..self.cursor].iter().map(|c| c.width().unwrap_or(0)).sum();
+        let mut skip = 0;
+        while offset >= width {
+            offset -= self.input[skip].width().unwrap_or(0);
+            skip += 1;
+        }
+        let text: String = self.input[skip..].iter().collect();
+        frame.render_widget(Paragraph::new(text).block(block), area);
+        frame.set_cursor(inner.x + offset as u16, inner.y);
+    }
+}
+
+fn draw_sidebar<B: Backend>(frame: &mut Frame<B>, area: Rect, session: &Session) {
+    let rooms: Vec<ListItem> = session
+        .rooms
+        .iter()
+        .map(|(room, members)| {
+            let text = format!("#{} ({})", room, members.len());
+            if session.room.as_ref() == Some(room) {
+                ListItem::new(Span::styled(text, Style::default().fg(Color::Blue).add_modifier(Modifier::BOLD)))
+            } else {
+                ListItem::new(text)
+            }
+        })
+        .collect();
+    let users: Vec<ListItem> = session
+        .room
+        .as_ref()
+        .and_then(|room| session.rooms.get(room))
+        .into_iter()
+        .flatten()
+        .map(|nick| ListItem::new(Span::styled(nick.clone(), nick_style(nick, &session.nick))))
+        .collect();
+
+    let rooms_height = (rooms.len() as u16 + 2).clamp(3, (area.height / 2).max(3));
+    let parts = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(rooms_height), Constraint::Min(0)])
+        .split(area);
+    frame.render_widget(List::new(rooms).block(Block::default().borders(Borders::ALL).title(" Rooms ")), parts[0]);
+    frame.render_widget(List::new(users).block(Block::default().borders(Borders::ALL).title(" Users ")), parts[1]);
+}
+
+// Makes the next (or previous) joined room the one messages go to.
+fn switch_room(session: &Mutex<Session>, forward: bool) {
+    let mut session = session.lock().unwrap();
+    let rooms: Vec<String> = session.rooms.keys().cloned().collect();
+    if rooms.is_empty() {
+        return;
+    }
+    let current = session.room.as_ref().and_then(|room| rooms.iter().position(|r| r == room));
+    let next = match (current, forward) {
+        (Some(i), true) => (i + 1) % rooms.len(),
+        (Some(i), false) => (i + rooms.len() - 1) % rooms.len(),
+        (None, _) => 0,
+    };
+    session.room = Some(rooms[next].clone());
+}
+
+pub fn run(session: &Mutex<Session>, outgoing: &Sender<ClientMessage>, lines: Receiver<Line>) -> io::Result<()> {
+    enable_raw_mode()?;
+    let _restore = Restore;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    let mut screen = Screen::new();
+
+    loop {
+        loop {
+            match lines.try_recv() {
+                Ok(line) => screen.push(line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if screen.quitting {
+                        return Ok(());
+                    }
+                    screen.connected = false;
+                    break;
+                }
+            }
+        }
+
+        terminal.draw(|frame| screen.draw(frame, &session.lock().unwrap()))?;
+
+        if event::poll(POLL_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if !screen.key(key, session, outgoing) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::view::Line;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Trust {
+    // Verify against the given CA certificate, or the bundled web PKI roots.
+    Roots(Option<PathBuf>),
+    // Accept whatever certificate the server presents the first time and pin
+    // its fingerprint in `known_servers` for every later connection.
+    FirstUse { known_servers: PathBuf },
+}
+
+pub fn connect(
+    stream: TcpStream,
+    endpoint: &str,
+    host: &str,
+    trust: &Trust,
+    display: &Sender<Line>,
+) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let config = client_config(endpoint, trust, display)?;
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+    let mut tls = StreamOwned::new(conn, stream);
+
+    let poll_timeout = tls.sock.read_timeout()?;
+    tls.sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+    tls.sock.set_read_timeout(poll_timeout)?;
+    Ok(tls)
+}
+
+fn client_config(endpoint: &str, trust: &Trust, display: &Sender<Line>) -> io::Result<Arc<ClientConfig>> {
+    let config = match trust {
+        Trust::Roots(ca_cert) => {
+            let mut roots = RootCertStore::empty();
+            match ca_cert {
+                Some(path) => {
+                    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                        roots.add(cert?).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()
+        }
+        Trust::FirstUse { known_servers } => {
+            let verifier = FirstUseVerifier {
+                known_servers: known_servers.clone(),
+                endpoint: endpoint.to_string(),
+                display: display.clone(),
+                provider: Arc::new(rustls::crypto::ring::default_provider()),
+            };
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn known_fingerprint(known_servers: &Path, endpoint: &str) -> io::Result<Option<String>> {
+    let contents = match fs::read_to_string(known_servers) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(known, _)| *known == endpoint)
+        .map(|(_, fingerprint)| fingerprint.trim().to_string()))
+}
+
+#[derive(Debug)]
+struct FirstUseVerifier {
+    known_servers: PathBuf,
+    endpoint: String,
+    display: Sender<Line>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FirstUseVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = fingerprint(end_entity);
+        let known = known_fingerprint(&self.known_servers, &self.endpoint)
+            .map_err(|e| rustls::Error::General(format!("cannot read {}: {}", self.known_servers.display(), e)))?;
+
+        match known {
+            Some(known) if known == presented => Ok(ServerCertVerified::assertion()),
+            Some(known) => Err(rustls::Error::General(format!(
+                "certificate for {} changed! pinned {}, got {} (remove the entry from {} if this is expected)",
+                self.endpoint,
+                known,
+                presented,
+                self.known_servers.display()
+            ))),
+            None => {
+                let pinned = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.known_servers)
+                    .and_then(|mut file| writeln!(file, "{} {}", self.endpoint, presented));
+                if let Err(e) = pinned {
+                    return Err(rustls::Error::General(format!(
+                        "cannot write {}: {}",
+                        self.known_servers.display(),
+                        e
+                    )));
+                }
+                let _ = self.display.send(Line::Info(format!("Trusting new certificate for {}", self.endpoint)));
+                let _ = self.display.send(Line::Info(format!("Fingerprint (SHA-256): {}", presented)));
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,111 @@
+use chrono::{Local, TimeZone};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// Lines shown around each match.
+const CONTEXT_LINES: usize = 2;
+// Only the most recent excerpts are shown.
+const MAX_EXCERPTS: usize = 20;
+
+// Local copies of what was said in each room, one file per room and day:
+// `<dir>/<room>/<YYYY-MM-DD>.log`.
+#[derive(Clone)]
+pub struct Transcript {
+    dir: PathBuf,
+}
+
+// A run of lines from one day's log, with the matching ones flagged.
+pub struct Excerpt {
+    pub room: String,
+    pub date: String,
+    pub lines: Vec<(String, bool)>,
+}
+
+pub struct Results {
+    pub excerpts: Vec<Excerpt>,
+    // Older excerpts that didn't make the cut.
+    pub omitted: usize,
+}
+
+// Room names come from the server; anything that could escape the log
+// directory is not logged.
+fn is_safe(room: &str) -> bool {
+    !room.is_empty() && room.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Transcript {
+    pub fn new(dir: PathBuf) -> Self {
+        Transcript { dir }
+    }
+
+    pub fn record(&self, room: &str, timestamp: i64, text: &str) -> io::Result<()> {
+        let Some(time) = Local.timestamp_opt(timestamp, 0).single() else {
+            return Ok(());
+        };
+        if !is_safe(room) {
+            return Ok(());
+        }
+        let dir = self.dir.join(room);
+        fs::create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.log", time.format("%Y-%m-%d"))))?;
+        writeln!(file, "[{}] {}", time.format("%H:%M:%S"), text)
+    }
+
+    // Case-insensitive search through every room's logs, oldest first.
+    pub fn search(&self, query: &str) -> io::Result<Results> {
+        let query = query.to_lowercase();
+        let mut logs = Vec::new();
+        let rooms = match fs::read_dir(&self.dir) {
+            Ok(rooms) => rooms,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Results { excerpts: Vec::new(), omitted: 0 }),
+            Err(e) => return Err(e),
+        };
+        for room in rooms {
+            let room = room?;
+            if !room.file_type()?.is_dir() {
+                continue;
+            }
+            for log in fs::read_dir(room.path())? {
+                let path = log?.path();
+                if let Some(date) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".log")) {
+                    logs.push((date.to_string(), room.file_name().to_string_lossy().into_owned(), path.clone()));
+                }
+            }
+        }
+        logs.sort();
+
+        let mut excerpts = Vec::new();
+        for (date, room, path) in logs {
+            let text = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = text.lines().collect();
+            // Overlapping context merges into one excerpt.
+            let mut ranges: Vec<(usize, usize)> = Vec::new();
+            for (i, _) in lines.iter().enumerate().filter(|(_, line)| line.to_lowercase().contains(&query)) {
+                let start = i.saturating_sub(CONTEXT_LINES);
+                let end = (i + CONTEXT_LINES + 1).min(lines.len());
+                match ranges.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+            }
+            for (start, end) in ranges {
+                excerpts.push(Excerpt {
+                    room: room.clone(),
+                    date: date.clone(),
+                    lines: lines[start..end]
+                        .iter()
+                        .map(|line| (line.to_string(), line.to_lowercase().contains(&query)))
+                        .collect(),
+                });
+            }
+        }
+
+        let omitted = excerpts.len().saturating_sub(MAX_EXCERPTS);
+        excerpts.drain(..omitted);
+        Ok(Results { excerpts, omitted })
+    }
+}
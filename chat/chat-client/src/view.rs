@@ -0,0 +1,53 @@
+use chrono::{Local, TimeZone};
+use std::fmt;
+
+// One line of output. It is kept structured so the full-screen UI can color
+// its parts; the plain line mode prints it as text.
+pub enum Line {
+    // A history replay carries no room.
+    Chat { time: String, room: Option<String>, from: String, text: String },
+    Dm { time: String, from: String, to: String, text: String, encrypted: bool },
+    // Something a user did in a room other than talking, like sharing a file.
+    Action { time: String, room: String, from: String, text: String },
+    // Joins, leaves and nick changes.
+    Event(String),
+    Info(String),
+    // Start and end of a history replay.
+    Marker(String),
+    Error(String),
+    // A line from a transcript search, either a match or context around one.
+    Found { text: String, hit: bool },
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Chat { time, room: Some(room), from, text } => write!(f, "[{}] #{} <{}> {}", time, room, from, text),
+            Line::Chat { time, room: None, from, text } => write!(f, "[{}] <{}> {}", time, from, text),
+            Line::Dm { time, from, to, text, encrypted: true } => write!(f, "[{}] [{} -> {}] {}", time, from, to, text),
+            Line::Dm { time, from, to, text, encrypted: false } => {
+                write!(f, "[{}] [{} -> {}] {} (unencrypted)", time, from, to, text)
+            }
+            Line::Action { time, room, from, text } => write!(f, "[{}] #{} * {} {}", time, room, from, text),
+            Line::Event(text) => write!(f, "* {}", text),
+            Line::Info(text) => write!(f, "*** {}", text),
+            Line::Marker(text) => write!(f, "--- {} ---", text),
+            Line::Error(text) => write!(f, "! {}", text),
+            Line::Found { text, hit: true } => write!(f, "> {}", text),
+            Line::Found { text, hit: false } => write!(f, "  {}", text),
+        }
+    }
+}
+
+// Messages from an earlier day (old history, offline messages) include the date.
+pub fn format_time(timestamp: i64) -> String {
+    let time = match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time,
+        None => return String::new(),
+    };
+    if time.date_naive() == Local::now().date_naive() {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
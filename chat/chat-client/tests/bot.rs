@@ -0,0 +1,141 @@
+use chat_client::bot::{self, Bot, BotError, Message, Trigger};
+use chat_protocol::{decode, encode, ClientMessage, ServerMessage};
+use chat_server::accounts::Accounts;
+use chat_server::files::FileStore;
+use chat_server::flood::FloodConfig;
+use chat_server::history::History;
+use chat_server::mailbox::Mailbox;
+use chat_server::moderation::Moderation;
+use chat_server::server::{Config, Server};
+use regex::Regex;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// An in-process server on an ephemeral port, as in chat-server's own tests.
+fn start_server() -> (SocketAddr, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        flood: FloodConfig { rate: 10_000.0, burst: 10_000.0, ..FloodConfig::default() },
+        ..Config::default()
+    };
+    let server = Arc::new(Server::new(
+        config,
+        History::open(dir.path().join("history")).unwrap(),
+        Accounts::open(dir.path().join("accounts.json")).unwrap(),
+        Moderation::open(dir.path().join("moderation.json")).unwrap(),
+        Mailbox::open(dir.path().join("mailbox.json")).unwrap(),
+        FileStore::open(dir.path().join("files"), 1024 * 1024).unwrap(),
+    ));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || chat_server::serve(listener, server, None));
+    (addr, dir)
+}
+
+// A scripted user that only looks at chat and DMs.
+struct User {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl User {
+    fn connect(addr: SocketAddr, nick: &str, room: &str) -> User {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut user = User { reader: BufReader::new(stream.try_clone().unwrap()), stream };
+        user.send(ClientMessage::Nick { nick: nick.to_string() });
+        user.send(ClientMessage::Join { room: room.to_string() });
+        user.next(|message| matches!(message, ServerMessage::Ack { command } if command == "join"));
+        user
+    }
+
+    fn send(&mut self, message: ClientMessage) {
+        self.stream.write_all(encode(&message).as_bytes()).unwrap();
+    }
+
+    fn next(&mut self, wanted: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "connection closed");
+            let message = decode(&line).unwrap();
+            if wanted(&message) {
+                return message;
+            }
+        }
+    }
+
+    // The next chat or DM from `from`, as (room, text); DMs have no room.
+    fn said_by(&mut self, from: &str) -> (Option<String>, String) {
+        let message = self.next(|message| match message {
+            ServerMessage::Chat { from: sender, .. } | ServerMessage::Dm { from: sender, .. } => sender == from,
+            _ => false,
+        });
+        match message {
+            ServerMessage::Chat { room, text, .. } => (Some(room), text),
+            ServerMessage::Dm { text, .. } => (None, text),
+            _ => unreachable!(),
+        }
+    }
+
+    // Waits until `nick` shows up in `room`.
+    fn sees_join(&mut self, room: &str, nick: &str) {
+        self.next(|message| matches!(message, ServerMessage::Join { room: r, nick: n } if r == room && n == nick));
+    }
+}
+
+#[test]
+fn bot_answers_in_rooms_and_dms() {
+    let (addr, _dir) = start_server();
+    let mut alice = User::connect(addr, "alice", "ci");
+
+    let mut echo = Bot::new(vec!["ci".to_string()]);
+    echo.nick = Some("echo".to_string());
+    echo.add_handler(Trigger { pattern: Regex::new(r"^!echo (.*)$").unwrap(), reply: "$1".to_string() });
+    echo.add_handler(|message: &Message| match message.room {
+        None => vec![format!("{} said {}", message.from, message.text)],
+        Some(_) => Vec::new(),
+    });
+    thread::spawn(move || echo.run(bot::connect(&addr.to_string()).unwrap(), None));
+    alice.sees_join("ci", "echo");
+
+    alice.send(ClientMessage::Chat { room: "ci".to_string(), text: "!echo hello there".to_string() });
+    assert_eq!(alice.said_by("echo"), (Some("ci".to_string()), "hello there".to_string()));
+
+    alice.send(ClientMessage::Dm { to: "echo".to_string(), text: "psst".to_string(), encrypted: false });
+    assert_eq!(alice.said_by("echo"), (None, "alice said psst".to_string()));
+}
+
+#[test]
+fn bot_posts_its_input_once_joined_and_quits_at_the_end() {
+    let (addr, _dir) = start_server();
+    let mut alice = User::connect(addr, "alice", "builds");
+
+    // All input is there before the bot even connects.
+    let (lines, input) = mpsc::channel();
+    for line in ["build 1 passed", "build 2 failed"] {
+        lines.send(line.to_string()).unwrap();
+    }
+    drop(lines);
+    let mut notifier = Bot::new(vec!["builds".to_string()]);
+    notifier.nick = Some("ci".to_string());
+    let result = notifier.run(bot::connect(&addr.to_string()).unwrap(), Some(input));
+    assert!(result.is_ok(), "{:?}", result);
+
+    assert_eq!(alice.said_by("ci"), (Some("builds".to_string()), "build 1 passed".to_string()));
+    assert_eq!(alice.said_by("ci"), (Some("builds".to_string()), "build 2 failed".to_string()));
+}
+
+#[test]
+fn bot_stops_when_its_nick_is_taken() {
+    let (addr, _dir) = start_server();
+    let _alice = User::connect(addr, "alice", "lobby");
+
+    let mut bot = Bot::new(vec!["lobby".to_string()]);
+    bot.nick = Some("alice".to_string());
+    let result = bot.run(bot::connect(&addr.to_string()).unwrap(), None);
+    assert!(matches!(result, Err(BotError::Rejected(_))), "{:?}", result);
+}
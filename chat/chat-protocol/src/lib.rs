@@ -0,0 +1,262 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// Every line on the wire is one JSON object carrying the protocol version in
+// `v` and the message kind in `type`, e.g.
+// {"v":1,"type":"chat","room":"lobby","text":"hello"}
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Chat { room: String, text: String },
+    Join { room: String },
+    Leave { room: String },
+    Nick { nick: String },
+    // With `encrypted` the text is sealed for the recipient (see the README)
+    // and the server passes it on without reading it.
+    Dm {
+        to: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "is_false")]
+        encrypted: bool,
+    },
+    History { room: String, limit: usize },
+    Register { nick: String, password: String },
+    Login { nick: String, password: String },
+    Kick { nick: String, reason: Option<String> },
+    // `target` is either a nickname or an IP address.
+    Ban { target: String, reason: Option<String> },
+    Unban { target: String },
+    Mute { nick: String, seconds: u64 },
+    Unmute { nick: String },
+    Op { nick: String },
+    Deop { nick: String },
+    // Answer to a server `ping`, echoing its token.
+    Pong { token: u64 },
+    // Sharing a file: `upload`, then the contents as base64 `upload_chunk`s,
+    // then `upload_end` with the SHA-256 (hex) of the whole file.
+    Upload { room: String, name: String, size: u64 },
+    UploadChunk { data: String },
+    UploadEnd { sha256: String },
+    Download { id: String },
+    // The client's public key for encrypted DMs (base64 X25519), and asking
+    // for someone else's, which is answered with `key`.
+    PublishKey { key: String },
+    GetKey { nick: String },
+    Quit,
+}
+
+impl ClientMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Chat { .. } => "chat",
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::Leave { .. } => "leave",
+            ClientMessage::Nick { .. } => "nick",
+            ClientMessage::Dm { .. } => "dm",
+            ClientMessage::History { .. } => "history",
+            ClientMessage::Register { .. } => "register",
+            ClientMessage::Login { .. } => "login",
+            ClientMessage::Kick { .. } => "kick",
+            ClientMessage::Ban { .. } => "ban",
+            ClientMessage::Unban { .. } => "unban",
+            ClientMessage::Mute { .. } => "mute",
+            ClientMessage::Unmute { .. } => "unmute",
+            ClientMessage::Op { .. } => "op",
+            ClientMessage::Deop { .. } => "deop",
+            ClientMessage::Pong { .. } => "pong",
+            ClientMessage::Upload { .. } => "upload",
+            ClientMessage::UploadChunk { .. } => "upload_chunk",
+            ClientMessage::UploadEnd { .. } => "upload_end",
+            ClientMessage::Download { .. } => "download",
+            ClientMessage::PublishKey { .. } => "publish_key",
+            ClientMessage::GetKey { .. } => "get_key",
+            ClientMessage::Quit => "quit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: i64,
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedFile {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    // Hex-encoded SHA-256 of the contents.
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome { id: usize, nick: String },
+    Chat { room: String, from: String, text: String, timestamp: i64 },
+    Join { room: String, nick: String },
+    Leave { room: String, nick: String },
+    Nick { old: String, new: String },
+    Dm {
+        from: String,
+        to: String,
+        text: String,
+        timestamp: i64,
+        #[serde(default, skip_serializing_if = "is_false")]
+        encrypted: bool,
+    },
+    History { room: String, messages: Vec<HistoryEntry> },
+    // Everyone in `room`, sent to a client right after it joined; later
+    // changes arrive as `join`, `leave` and `nick`.
+    Members { room: String, nicks: Vec<String> },
+    System { text: String },
+    Error { code: ErrorCode, message: String },
+    Ack { command: String },
+    // Heartbeat sent to idle clients, which must reply with a `pong` before
+    // the server's timeout runs out or they are disconnected.
+    Ping { token: u64 },
+    // A file was shared in `room`; fetch it with `download`. (Boxed since
+    // it would be the largest variant by far.)
+    File { room: String, from: String, file: Box<SharedFile>, timestamp: i64 },
+    // Answer to `download`: the file's contents as base64 chunks between start and end.
+    FileStart { file: SharedFile },
+    FileChunk { id: String, data: String },
+    FileEnd { id: String },
+    // Answer to `get_key`; no key if the user is unknown or their client
+    // doesn't do encryption.
+    Key { nick: String, key: Option<String> },
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error { code, message: message.into() }
+    }
+
+    pub fn system(text: impl Into<String>) -> Self {
+        ServerMessage::System { text: text.into() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    UnsupportedVersion,
+    InvalidName,
+    NickInUse,
+    NickReserved,
+    Unauthenticated,
+    InvalidCredentials,
+    PermissionDenied,
+    Banned,
+    Muted,
+    RateLimited,
+    MessageTooLong,
+    NotInRoom,
+    NoSuchUser,
+    NoSuchFile,
+    FileTooLarge,
+    TransferFailed,
+    Internal,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Json(serde_json::Error),
+    MissingVersion,
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Json(e) => write!(f, "invalid message: {}", e),
+            ProtocolError::MissingVersion => write!(f, "missing protocol version"),
+            ProtocolError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported protocol version {} (expected {})",
+                v, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(e: serde_json::Error) -> Self {
+        ProtocolError::Json(e)
+    }
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            _ => ErrorCode::InvalidMessage,
+        }
+    }
+}
+
+// Serializes a message as a single JSON line, including the trailing newline.
+pub fn encode<T: Serialize>(message: &T) -> String {
+    let mut value = serde_json::to_value(message).expect("protocol messages always serialize");
+    if let Value::Object(map) = &mut value {
+        map.insert("v".to_string(), Value::from(PROTOCOL_VERSION));
+    }
+    let mut line = value.to_string();
+    line.push('\n');
+    line
+}
+
+pub fn decode<T: DeserializeOwned>(line: &str) -> Result<T, ProtocolError> {
+    let mut value: Value = serde_json::from_str(line.trim_end())?;
+    let version = match &mut value {
+        Value::Object(map) => map.remove("v").and_then(|v| v.as_u64()),
+        _ => None,
+    };
+    match version {
+        Some(v) if v == PROTOCOL_VERSION as u64 => Ok(serde_json::from_value(value)?),
+        Some(v) => Err(ProtocolError::UnsupportedVersion(v)),
+        None => Err(ProtocolError::MissingVersion),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip_with_the_version() {
+        let message = ClientMessage::Chat { room: "lobby".to_string(), text: "hi".to_string() };
+        let line = encode(&message);
+        assert!(line.ends_with('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["v"], PROTOCOL_VERSION);
+        assert_eq!(decode::<ClientMessage>(&line).unwrap(), message);
+    }
+
+    #[test]
+    fn a_missing_version_is_an_invalid_message() {
+        let error = decode::<ClientMessage>(r#"{"type":"quit"}"#).unwrap_err();
+        assert!(matches!(error, ProtocolError::MissingVersion));
+        assert_eq!(error.code(), ErrorCode::InvalidMessage);
+    }
+
+    #[test]
+    fn other_versions_are_unsupported() {
+        let error = decode::<ClientMessage>(r#"{"v":2,"type":"quit"}"#).unwrap_err();
+        assert!(matches!(error, ProtocolError::UnsupportedVersion(2)));
+        assert_eq!(error.code(), ErrorCode::UnsupportedVersion);
+    }
+}
@@ -0,0 +1,107 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    password_hash: String,
+    created: i64,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    Exists,
+    Hash(argon2::password_hash::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Exists => write!(f, "account already exists"),
+            AccountError::Hash(e) => write!(f, "password hashing failed: {}", e),
+            AccountError::Io(e) => write!(f, "could not save accounts: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AccountError {
+    fn from(e: io::Error) -> Self {
+        AccountError::Io(e)
+    }
+}
+
+// Registered accounts, keyed by nickname and stored as a JSON file holding
+// salted argon2 hashes.
+pub struct Accounts {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl Accounts {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let accounts = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Accounts {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(nick)
+    }
+
+    pub fn register(&self, nick: &str, password: &str) -> Result<(), AccountError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(AccountError::Hash)?
+            .to_string();
+
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(nick) {
+            return Err(AccountError::Exists);
+        }
+        accounts.insert(nick.to_string(), Account {
+            password_hash,
+            created: Local::now().timestamp(),
+        });
+        if let Err(e) = self.save(&accounts) {
+            accounts.remove(nick);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, nick: &str, password: &str) -> bool {
+        let password_hash = match self.accounts.lock().unwrap().get(nick) {
+            Some(account) => account.password_hash.clone(),
+            None => return false,
+        };
+        match PasswordHash::new(&password_hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn save(&self, accounts: &HashMap<String, Account>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(accounts)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
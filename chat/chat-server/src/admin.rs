@@ -0,0 +1,147 @@
+use crate::server::Server;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a shutdown waits for connections to drain before exiting anyway.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const HELP: &str = "Commands: clients, stats, notice <text>, shutdown [reason], quit";
+
+// Whoever can connect can shut the server down, so the socket is bound in a
+// directory only we can enter and made private there before it is moved into
+// place. Binding at `path` and then restricting it would leave a window in
+// which anyone could connect.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("admin.sock");
+    let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = dir.join("socket");
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+// Line based admin interface on a Unix socket, e.g. `nc -U admin.sock`.
+pub fn listen(path: &Path, server: Arc<Server>) -> io::Result<()> {
+    // Replace a socket left behind by an earlier run, but nothing else.
+    if fs::symlink_metadata(path).is_ok_and(|meta| !meta.file_type().is_socket()) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    let listener = bind_private(path)?;
+    println!("Admin socket listening on {}", path.display());
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    if let Err(e) = handle_admin(stream, &server) {
+                        eprintln!("Error on admin connection: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting admin connection: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_admin(stream: UnixStream, server: &Server) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let (command, arg) = match line.trim().split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line.trim(), ""),
+        };
+        match command {
+            "" => {}
+            "clients" => write_clients(&mut writer, server)?,
+            "stats" => write_stats(&mut writer, server)?,
+            "notice" if !arg.is_empty() => {
+                let count = server.notice(arg);
+                writeln!(writer, "Notice sent to {} clients", count)?;
+            }
+            "shutdown" => {
+                writeln!(writer, "Shutting down")?;
+                shutdown(server, Some(arg).filter(|reason| !reason.is_empty()));
+            }
+            "quit" => break,
+            _ => writeln!(writer, "{}", HELP)?,
+        }
+    }
+    Ok(())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn write_clients(writer: &mut impl Write, server: &Server) -> io::Result<()> {
+    let clients = server.clients();
+    writeln!(
+        writer,
+        "{:<5} {:<22} {:<16} {:<16} {:>10} {:>10} {:>8} {:>8}  ROOMS",
+        "ID", "ADDRESS", "NICK", "ACCOUNT", "IN", "OUT", "ONLINE", "IDLE"
+    )?;
+    for client in &clients {
+        let rooms: Vec<String> = client.rooms.iter().map(|room| format!("#{}", room)).collect();
+        writeln!(
+            writer,
+            "{:<5} {:<22} {:<16} {:<16} {:>10} {:>10} {:>8} {:>8}  {}",
+            client.id,
+            client.addr.to_string(),
+            client.nick,
+            client.account.as_deref().unwrap_or("-"),
+            client.received,
+            client.sent,
+            format_duration(client.connected),
+            format_duration(client.idle),
+            rooms.join(" ")
+        )?;
+    }
+    writeln!(writer, "{} clients", clients.len())
+}
+
+fn write_stats(writer: &mut impl Write, server: &Server) -> io::Result<()> {
+    let stats = server.stats();
+    writeln!(writer, "Uptime:         {}", format_duration(stats.uptime))?;
+    writeln!(writer, "Clients:        {}", stats.clients)?;
+    writeln!(writer, "Rooms:          {}", stats.rooms)?;
+    let links = if stats.links.is_empty() { "-".to_string() } else { stats.links.join(", ") };
+    writeln!(writer, "Linked servers: {}", links)?;
+    writeln!(writer, "Bytes received: {}", stats.received)?;
+    writeln!(writer, "Bytes sent:     {}", stats.sent)
+}
+
+// Closes every connection, gives them a moment to flush and exits.
+fn shutdown(server: &Server, reason: Option<&str>) {
+    println!("Shutting down{}", reason.map(|reason| format!(": {}", reason)).unwrap_or_default());
+    server.shutdown(reason);
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while server.client_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    process::exit(0);
+}
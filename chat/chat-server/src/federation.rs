@@ -0,0 +1,508 @@
+use crate::server::{is_valid_name, is_valid_text, Server};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_protocol::{decode, encode, ServerMessage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// How many relayed events are remembered to recognise ones that come back
+// around a loop in the link topology.
+const MAX_SEEN: usize = 10_000;
+// Longest line accepted once a link is up. Sync lists every user of every
+// server, so this is well above the limit for client lines, which applies
+// during the handshake.
+const MAX_LINK_LINE: usize = 1024 * 1024;
+
+// Nicknames per room.
+pub type Rooms = HashMap<String, HashSet<String>>;
+
+// Something a server's own users did, relayed to every linked server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    Chat { room: String, from: String, text: String, timestamp: i64 },
+    Join { room: String, nick: String },
+    Leave { room: String, nick: String },
+    Nick { old: String, new: String },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LinkMessage {
+    // Each side sends a random nonce and answers the other's with an Auth
+    // proving it knows the link secret; nothing else is accepted before that.
+    Hello { server: String, nonce: String },
+    Auth { proof: String },
+    // Flooded across all links; `origin` and `seq` identify the event so
+    // every server handles and forwards it only once.
+    Event { origin: String, seq: u64, event: Event },
+    // Who is in which room on each server the sender knows about, replacing
+    // whatever the receiver knew about those servers.
+    Sync { servers: HashMap<String, Rooms> },
+    // These servers are no longer reachable through the sender.
+    Gone { servers: Vec<String> },
+}
+
+struct Link {
+    peer: String,
+    outbox: Sender<LinkMessage>,
+}
+
+// A server whose users are known, and the link they were learned from.
+struct Remote {
+    via: usize,
+    rooms: Rooms,
+}
+
+#[derive(Default)]
+struct State {
+    links: HashMap<usize, Link>,
+    remotes: HashMap<String, Remote>,
+    seen: HashSet<(String, u64)>,
+    seen_order: VecDeque<(String, u64)>,
+}
+
+impl State {
+    // Returns false if the event was handled before.
+    fn mark_seen(&mut self, origin: &str, seq: u64) -> bool {
+        let key = (origin.to_string(), seq);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn send_except(&self, except: usize, message: impl Fn() -> LinkMessage) {
+        for (&id, link) in &self.links {
+            if id != except {
+                let _ = link.outbox.send(message());
+            }
+        }
+    }
+}
+
+// Proves `from` knows the link secret. Both ends' names and nonces go in, so a
+// proof is only good for this one connection and in this one direction.
+fn link_proof(secret: &str, from: (&str, &str), to: (&str, &str)) -> String {
+    hex::encode(Sha256::digest(format!("{}\n{}\n{}\n{}\n{}", secret, from.0, from.1, to.0, to.1)))
+}
+
+// Reads a line of at most `limit` bytes. A longer one ends the link, since
+// the peer is broken or hostile either way.
+fn read_link_line(reader: &mut BufReader<TcpStream>, line: &mut String, limit: usize) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    // Reading one byte past the limit tells an over-long line apart from one that just fits.
+    let read = reader.by_ref().take(limit as u64 + 1).read_until(b'\n', &mut bytes)?;
+    if bytes.len() > limit && !bytes.ends_with(b"\n") {
+        return Err(io::Error::new(ErrorKind::InvalidData, "line from linked server is too long"));
+    }
+    *line = String::from_utf8(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok(read)
+}
+
+// Compares without returning early, so timing doesn't reveal how much of a proof was right.
+fn proofs_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Remote events end up in local history and in front of local clients, so
+// they get the same checks as local input.
+fn valid_event(event: &Event, max_text_len: usize) -> bool {
+    match event {
+        Event::Chat { room, from, text, .. } => {
+            is_valid_name(room) && is_valid_name(from) && is_valid_text(text) && text.len() <= max_text_len
+        }
+        Event::Join { room, nick } | Event::Leave { room, nick } => is_valid_name(room) && is_valid_name(nick),
+        Event::Nick { old, new } => is_valid_name(old) && is_valid_name(new),
+    }
+}
+
+fn valid_rooms(rooms: &Rooms) -> bool {
+    rooms.iter().all(|(room, nicks)| is_valid_name(room) && nicks.iter().all(|nick| is_valid_name(nick)))
+}
+
+// Users of other servers show up as nick@server.
+fn qualify(nick: &str, server: &str) -> String {
+    format!("{}@{}", nick, server)
+}
+
+// Local messages announcing the difference between two snapshots of a server's rooms.
+fn membership_changes(server: &str, old: &Rooms, new: &Rooms) -> Vec<(Vec<String>, ServerMessage)> {
+    let empty = HashSet::new();
+    let mut changes = Vec::new();
+    for (room, nicks) in old {
+        for nick in nicks.difference(new.get(room).unwrap_or(&empty)) {
+            changes.push((vec![room.clone()], ServerMessage::Leave { room: room.clone(), nick: qualify(nick, server) }));
+        }
+    }
+    for (room, nicks) in new {
+        for nick in nicks.difference(old.get(room).unwrap_or(&empty)) {
+            changes.push((vec![room.clone()], ServerMessage::Join { room: room.clone(), nick: qualify(nick, server) }));
+        }
+    }
+    changes
+}
+
+pub struct Federation {
+    name: String,
+    secret: String,
+    state: Mutex<State>,
+    next_seq: AtomicU64,
+    next_link: AtomicUsize,
+}
+
+impl Federation {
+    pub fn new(name: impl Into<String>, secret: impl Into<String>) -> Self {
+        // Starting from the clock keeps sequence numbers from repeating after
+        // a restart, which peers would otherwise drop as already seen.
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        Federation {
+            name: name.into(),
+            secret: secret.into(),
+            state: Mutex::new(State::default()),
+            next_seq: AtomicU64::new(start),
+            next_link: AtomicUsize::new(0),
+        }
+    }
+
+    // Relays something a local user did to all linked servers.
+    pub fn publish(&self, event: Event) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let state = self.state.lock().unwrap();
+        state.send_except(usize::MAX, || LinkMessage::Event {
+            origin: self.name.clone(),
+            seq,
+            event: event.clone(),
+        });
+    }
+
+    // Names of the directly linked servers.
+    pub fn links(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut links: Vec<String> = state.links.values().map(|link| link.peer.clone()).collect();
+        links.sort();
+        links
+    }
+
+    pub fn remote_members(&self, room: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .remotes
+            .iter()
+            .flat_map(|(server, remote)| {
+                remote
+                    .rooms
+                    .get(room)
+                    .into_iter()
+                    .flatten()
+                    .map(move |nick| qualify(nick, server))
+            })
+            .collect()
+    }
+
+    fn add_link(&self, peer: &str, outbox: Sender<LinkMessage>) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        if peer == self.name || state.links.values().any(|link| link.peer == peer) {
+            return None;
+        }
+        let id = self.next_link.fetch_add(1, Ordering::Relaxed);
+        state.links.insert(id, Link { peer: peer.to_string(), outbox });
+        Some(id)
+    }
+
+    // Tells a newly linked server about every user known here, except the
+    // ones it told us about itself.
+    fn link_up(&self, link: usize, server: &Server) {
+        let local = server.local_rooms();
+        let state = self.state.lock().unwrap();
+        let mut servers: HashMap<String, Rooms> = state
+            .remotes
+            .iter()
+            .filter(|(_, remote)| remote.via != link)
+            .map(|(name, remote)| (name.clone(), remote.rooms.clone()))
+            .collect();
+        servers.insert(self.name.clone(), local);
+        if let Some(link) = state.links.get(&link) {
+            let _ = link.outbox.send(LinkMessage::Sync { servers });
+        }
+    }
+
+    // Forgets the link and every server that was only reachable through it.
+    fn link_down(&self, link: usize, server: &Server) {
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            state.links.remove(&link);
+            let gone: Vec<String> = state
+                .remotes
+                .iter()
+                .filter(|(_, remote)| remote.via == link)
+                .map(|(name, _)| name.clone())
+                .collect();
+            self.forget(&mut state, link, gone)
+        };
+        for (rooms, message) in changes {
+            server.deliver_remote(&rooms, &message);
+        }
+    }
+
+    // Drops the given servers' users and tells the other links they are gone.
+    fn forget(&self, state: &mut State, from: usize, servers: Vec<String>) -> Vec<(Vec<String>, ServerMessage)> {
+        let mut changes = Vec::new();
+        let mut removed = Vec::new();
+        for name in servers {
+            if let Some(remote) = state.remotes.remove(&name) {
+                println!("Server {} is no longer reachable", name);
+                changes.extend(membership_changes(&name, &remote.rooms, &Rooms::new()));
+                removed.push(name);
+            }
+        }
+        if !removed.is_empty() {
+            state.send_except(from, || LinkMessage::Gone { servers: removed.clone() });
+        }
+        changes
+    }
+
+    fn receive(&self, link: usize, message: LinkMessage, server: &Server) {
+        // Taken up front since the client map is never locked while holding the state.
+        let local = matches!(message, LinkMessage::Gone { .. }).then(|| server.local_rooms());
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            match message {
+                LinkMessage::Hello { .. } | LinkMessage::Auth { .. } => Vec::new(),
+                LinkMessage::Event { origin, seq, event } => {
+                    if !is_valid_name(&origin) || !valid_event(&event, server.max_line_len()) {
+                        eprintln!("Dropping invalid event from server {}", origin);
+                        return;
+                    }
+                    if origin == self.name || !state.mark_seen(&origin, seq) {
+                        return;
+                    }
+                    state.send_except(link, || LinkMessage::Event {
+                        origin: origin.clone(),
+                        seq,
+                        event: event.clone(),
+                    });
+                    let remote = state.remotes.entry(origin.clone()).or_insert_with(|| Remote {
+                        via: link,
+                        rooms: Rooms::new(),
+                    });
+                    apply(&origin, &mut remote.rooms, event)
+                }
+                LinkMessage::Sync { servers } => {
+                    let mut changes = Vec::new();
+                    let mut updated = HashMap::new();
+                    for (name, rooms) in servers {
+                        if name == self.name {
+                            continue;
+                        }
+                        if !is_valid_name(&name) || !valid_rooms(&rooms) {
+                            eprintln!("Ignoring invalid room list for server {}", name);
+                            continue;
+                        }
+                        let old = state.remotes.get(&name).map(|remote| &remote.rooms);
+                        if old == Some(&rooms) {
+                            continue;
+                        }
+                        changes.extend(membership_changes(&name, old.unwrap_or(&Rooms::new()), &rooms));
+                        state.remotes.insert(name.clone(), Remote { via: link, rooms: rooms.clone() });
+                        updated.insert(name, rooms);
+                    }
+                    if !updated.is_empty() {
+                        state.send_except(link, || LinkMessage::Sync { servers: updated.clone() });
+                    }
+                    changes
+                }
+                LinkMessage::Gone { servers } => {
+                    // Only trust the sender about servers we learned from it. If
+                    // we still reach some of them another way (or it lost us),
+                    // tell it about them again so it can route through us.
+                    let (gone, reachable): (Vec<String>, Vec<String>) = servers
+                        .into_iter()
+                        .partition(|name| state.remotes.get(name).is_some_and(|remote| remote.via == link));
+                    let mut servers: HashMap<String, Rooms> = HashMap::new();
+                    for name in reachable {
+                        if name == self.name {
+                            servers.insert(name, local.clone().unwrap_or_default());
+                        } else if let Some(remote) = state.remotes.get(&name) {
+                            servers.insert(name, remote.rooms.clone());
+                        }
+                    }
+                    if let (false, Some(sender)) = (servers.is_empty(), state.links.get(&link)) {
+                        let _ = sender.outbox.send(LinkMessage::Sync { servers });
+                    }
+                    self.forget(&mut state, link, gone)
+                }
+            }
+        };
+        for (rooms, message) in changes {
+            server.deliver_remote(&rooms, &message);
+        }
+    }
+}
+
+// Updates a remote server's rooms for one of its events and returns what
+// local clients should see.
+fn apply(origin: &str, rooms: &mut Rooms, event: Event) -> Vec<(Vec<String>, ServerMessage)> {
+    match event {
+        Event::Chat { room, from, text, timestamp } => {
+            let from = qualify(&from, origin);
+            vec![(vec![room.clone()], ServerMessage::Chat { room, from, text, timestamp })]
+        }
+        Event::Join { room, nick } => {
+            if !rooms.entry(room.clone()).or_default().insert(nick.clone()) {
+                return Vec::new();
+            }
+            vec![(vec![room.clone()], ServerMessage::Join { room, nick: qualify(&nick, origin) })]
+        }
+        Event::Leave { room, nick } => {
+            let removed = rooms.get_mut(&room).is_some_and(|nicks| nicks.remove(&nick));
+            if rooms.get(&room).is_some_and(|nicks| nicks.is_empty()) {
+                rooms.remove(&room);
+            }
+            if !removed {
+                return Vec::new();
+            }
+            vec![(vec![room.clone()], ServerMessage::Leave { room, nick: qualify(&nick, origin) })]
+        }
+        Event::Nick { old, new } => {
+            let mut renamed_in = Vec::new();
+            for (room, nicks) in rooms.iter_mut() {
+                if nicks.remove(&old) {
+                    nicks.insert(new.clone());
+                    renamed_in.push(room.clone());
+                }
+            }
+            if renamed_in.is_empty() {
+                return Vec::new();
+            }
+            vec![(renamed_in, ServerMessage::Nick { old: qualify(&old, origin), new: qualify(&new, origin) })]
+        }
+    }
+}
+
+pub fn listen(addr: &str, server: Arc<Server>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Accepting server links on {}", addr);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    if let Err(e) = run_link(stream, &server) {
+                        eprintln!("Server link failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting server link: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Keeps a link to `peer` up, reconnecting with a growing delay whenever it drops.
+pub fn connect(peer: String, server: Arc<Server>) {
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        match TcpStream::connect(&peer) {
+            Ok(stream) => {
+                delay = MIN_RETRY_DELAY;
+                if let Err(e) = run_link(stream, &server) {
+                    eprintln!("Link to {} failed: {}", peer, e);
+                }
+            }
+            Err(e) => eprintln!("Could not link to {}: {}", peer, e),
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+fn run_link(stream: TcpStream, server: &Server) -> io::Result<()> {
+    let federation = server.federation();
+    let mut writer = stream.try_clone()?;
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let nonce = hex::encode(nonce);
+    writer.write_all(encode(&LinkMessage::Hello { server: federation.name.clone(), nonce: nonce.clone() }).as_bytes())?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    read_link_line(&mut reader, &mut line, server.max_line_len())?;
+    let (peer, peer_nonce) = match decode::<LinkMessage>(&line) {
+        Ok(LinkMessage::Hello { server, nonce }) if is_valid_name(&server) => (server, nonce),
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "peer did not introduce itself")),
+    };
+    // Checked before answering, so a peer can't get our proof by using our name.
+    if peer == federation.name {
+        return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} is this server", peer)));
+    }
+    let (us, them) = ((federation.name.as_str(), nonce.as_str()), (peer.as_str(), peer_nonce.as_str()));
+    let proof = link_proof(&federation.secret, us, them);
+    writer.write_all(encode(&LinkMessage::Auth { proof }).as_bytes())?;
+
+    read_link_line(&mut reader, &mut line, server.max_line_len())?;
+    stream.set_read_timeout(None)?;
+    match decode::<LinkMessage>(&line) {
+        Ok(LinkMessage::Auth { proof }) if proofs_match(&proof, &link_proof(&federation.secret, them, us)) => {}
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("server {} does not know the link secret", peer),
+            ))
+        }
+    }
+
+    let (outbox, messages) = mpsc::channel();
+    let link = federation.add_link(&peer, outbox).ok_or_else(|| {
+        io::Error::new(ErrorKind::AlreadyExists, format!("already linked to {} (or it is this server)", peer))
+    })?;
+    println!("Linked with server {}", peer);
+
+    let writer_thread = thread::spawn(move || {
+        for message in messages {
+            if writer.write_all(encode(&message).as_bytes()).is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+    federation.link_up(link, server);
+
+    let result = loop {
+        match read_link_line(&mut reader, &mut line, MAX_LINK_LINE) {
+            Ok(0) => break Ok(()),
+            Ok(_) => match decode::<LinkMessage>(&line) {
+                Ok(message) => federation.receive(link, message, server),
+                Err(e) => eprintln!("Unreadable message from server {}: {}", peer, e),
+            },
+            Err(e) => break Err(e),
+        }
+    };
+
+    // Dropping the link's outbox stops the writer.
+    federation.link_down(link, server);
+    let _ = writer_thread.join();
+    println!("Link with server {} closed", peer);
+    result
+}
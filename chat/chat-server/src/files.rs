@@ -0,0 +1,240 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chat_protocol::{ErrorCode, ServerMessage, SharedFile};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Length of the random ids handed out to clients, in hex digits.
+const ID_LEN: usize = 16;
+// Raw bytes per `file_chunk` sent to downloading clients.
+const DOWNLOAD_CHUNK_SIZE: usize = 32 * 1024;
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug)]
+pub enum FileError {
+    TooLarge { limit: u64 },
+    Incomplete { expected: u64, received: u64 },
+    HashMismatch,
+    Io(io::Error),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::TooLarge { limit } => write!(f, "files are limited to {} bytes", limit),
+            FileError::Incomplete { expected, received } => {
+                write!(f, "upload incomplete: expected {} bytes, received {} bytes", expected, received)
+            }
+            FileError::HashMismatch => write!(f, "SHA-256 of the upload does not match"),
+            FileError::Io(e) => write!(f, "could not store file: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        FileError::Io(e)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub room: String,
+    pub from: String,
+    pub timestamp: i64,
+}
+
+impl StoredFile {
+    pub fn shared(&self) -> SharedFile {
+        SharedFile {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+        }
+    }
+}
+
+// A file being uploaded. It is written to a temporary file that is removed
+// again if the upload is dropped before it finished.
+pub struct Upload {
+    pub room: String,
+    name: String,
+    size: u64,
+    received: u64,
+    hasher: Sha256,
+    file: File,
+    path: PathBuf,
+}
+
+impl Upload {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), FileError> {
+        let received = self.received + data.len() as u64;
+        if received > self.size {
+            return Err(FileError::Incomplete { expected: self.size, received });
+        }
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.received = received;
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Keeps only the last path component and rejects names that can't be shown.
+pub fn clean_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    if name.len() > MAX_NAME_LEN || name.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// Shared files. Contents are stored once under their SHA-256, and each time a
+// file is shared it gets its own id and a JSON file describing that share, so
+// sharing the same contents again (say in another room) doesn't change who
+// shared it where before.
+pub struct FileStore {
+    dir: PathBuf,
+    max_size: u64,
+    // Numbers uploads, so each has a temporary file of its own.
+    next_upload: AtomicU64,
+}
+
+impl FileStore {
+    pub fn open(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir, max_size, next_upload: AtomicU64::new(0) })
+    }
+
+    pub fn begin(&self, client: usize, room: &str, name: &str, size: u64) -> Result<Upload, FileError> {
+        if size > self.max_size {
+            return Err(FileError::TooLarge { limit: self.max_size });
+        }
+        let upload = self.next_upload.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("upload-{}-{}.part", client, upload));
+        Ok(Upload {
+            room: room.to_string(),
+            name: name.to_string(),
+            size,
+            received: 0,
+            hasher: Sha256::new(),
+            file: File::create(&path)?,
+            path,
+        })
+    }
+
+    // Checks the upload against the size announced at the start and the hash
+    // sent at the end, then moves it into place.
+    pub fn finish(&self, mut upload: Upload, sha256: &str, from: &str) -> Result<StoredFile, FileError> {
+        if upload.received != upload.size {
+            return Err(FileError::Incomplete { expected: upload.size, received: upload.received });
+        }
+        let hash = hex::encode(std::mem::take(&mut upload.hasher).finalize());
+        if !hash.eq_ignore_ascii_case(sha256) {
+            return Err(FileError::HashMismatch);
+        }
+        upload.file.sync_all()?;
+
+        let blob = self.dir.join(&hash);
+        if !blob.exists() {
+            fs::rename(&upload.path, &blob)?;
+        }
+
+        let mut stored = StoredFile {
+            id: String::new(),
+            name: upload.name.clone(),
+            size: upload.size,
+            sha256: hash,
+            room: upload.room.clone(),
+            from: from.to_string(),
+            timestamp: Local::now().timestamp(),
+        };
+        let mut meta = loop {
+            let mut id = [0u8; ID_LEN / 2];
+            OsRng.fill_bytes(&mut id);
+            stored.id = hex::encode(id);
+            match File::options().write(true).create_new(true).open(self.meta_path(&stored.id)) {
+                Ok(meta) => break meta,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        meta.write_all(serde_json::to_string_pretty(&stored).map_err(io::Error::from)?.as_bytes())?;
+        Ok(stored)
+    }
+
+    pub fn get(&self, id: &str) -> io::Result<Option<(StoredFile, PathBuf)>> {
+        if id.len() != ID_LEN || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let id = id.to_ascii_lowercase();
+        let stored: StoredFile = match fs::read_to_string(self.meta_path(&id)) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let path = self.dir.join(&stored.sha256);
+        Ok(Some((stored, path)))
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+// A shared file on its way to a client, read a chunk at a time as the
+// transport writes it out: `file_start`, the chunks, then `file_end`.
+pub struct Download {
+    stored: StoredFile,
+    file: Option<File>,
+    started: bool,
+}
+
+impl Download {
+    pub fn open(stored: StoredFile, path: &Path) -> io::Result<Self> {
+        Ok(Download { file: Some(File::open(path)?), stored, started: false })
+    }
+}
+
+impl Iterator for Download {
+    type Item = ServerMessage;
+
+    fn next(&mut self) -> Option<ServerMessage> {
+        if !std::mem::replace(&mut self.started, true) {
+            return Some(ServerMessage::FileStart { file: self.stored.shared() });
+        }
+        let file = self.file.as_mut()?;
+        let mut chunk = Vec::with_capacity(DOWNLOAD_CHUNK_SIZE);
+        match file.take(DOWNLOAD_CHUNK_SIZE as u64).read_to_end(&mut chunk) {
+            Ok(0) => {
+                self.file = None;
+                Some(ServerMessage::FileEnd { id: self.stored.id.clone() })
+            }
+            Ok(_) => Some(ServerMessage::FileChunk { id: self.stored.id.clone(), data: BASE64.encode(&chunk) }),
+            // Without a file_end the client discards what it got so far.
+            Err(e) => {
+                eprintln!("Error reading shared file {}: {}", self.stored.id, e);
+                self.file = None;
+                Some(ServerMessage::error(ErrorCode::Internal, "Could not read the file"))
+            }
+        }
+    }
+}
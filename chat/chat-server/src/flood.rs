@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+// Violations are forgotten after this long without a new one.
+const VIOLATION_DECAY: Duration = Duration::from_secs(60);
+// Further violations this soon after a penalty are dropped without escalating,
+// so one burst doesn't go straight from a warning to a disconnect.
+const VIOLATION_COOLDOWN: Duration = Duration::from_secs(2);
+const WARNINGS_BEFORE_MUTE: u32 = 2;
+const MUTES_BEFORE_DISCONNECT: u32 = 2;
+const FLOOD_MUTE: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+pub struct FloodConfig {
+    // Sustained messages per second a client may send.
+    pub rate: f64,
+    // How many messages may be sent in a quick burst before the rate applies.
+    pub burst: f64,
+    pub max_line_len: usize,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            rate: 2.0,
+            burst: 10.0,
+            max_line_len: 4096,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Penalty {
+    Warn,
+    Mute(Duration),
+    Disconnect,
+}
+
+// Token bucket rate limiter plus a violation counter that escalates from
+// warnings to a temporary mute to disconnecting the client.
+pub struct FloodState {
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+    last_violation: Instant,
+    // Set by a mute penalty. It lives with the connection's flood state so it
+    // can't be shed by changing nick.
+    muted_until: Option<Instant>,
+}
+
+impl FloodState {
+    pub fn new(config: &FloodConfig) -> Self {
+        let now = Instant::now();
+        FloodState {
+            tokens: config.burst,
+            last_refill: now,
+            violations: 0,
+            last_violation: now,
+            muted_until: None,
+        }
+    }
+
+    pub fn allow(&mut self, config: &FloodConfig) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate).min(config.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn violation(&mut self) -> Option<Penalty> {
+        let now = Instant::now();
+        let since_last = now.duration_since(self.last_violation);
+        if self.violations > 0 && since_last < VIOLATION_COOLDOWN {
+            return None;
+        }
+        if since_last > VIOLATION_DECAY {
+            self.violations = 0;
+        }
+        self.last_violation = now;
+        self.violations += 1;
+
+        Some(if self.violations <= WARNINGS_BEFORE_MUTE {
+            Penalty::Warn
+        } else if self.violations <= WARNINGS_BEFORE_MUTE + MUTES_BEFORE_DISCONNECT {
+            self.muted_until = Some(now + FLOOD_MUTE);
+            Penalty::Mute(FLOOD_MUTE)
+        } else {
+            Penalty::Disconnect
+        })
+    }
+
+    // Remaining time of a flood mute, if any.
+    pub fn muted_for(&self) -> Option<Duration> {
+        self.muted_until.and_then(|until| until.checked_duration_since(Instant::now())).filter(|left| !left.is_zero())
+    }
+}
@@ -0,0 +1,33 @@
+use crate::server::Server;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+pub struct HeartbeatConfig {
+    // A client that has been quiet this long is sent a ping.
+    pub interval: Duration,
+    // A client that has been quiet this long (ping or not) is dropped.
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+// Periodically pings idle clients and reaps the ones that stopped answering.
+pub fn run(server: Arc<Server>) {
+    let config = server.heartbeat_config();
+    let check_every = (config.interval.min(config.timeout) / 4).max(MIN_CHECK_INTERVAL);
+    loop {
+        thread::sleep(check_every);
+        server.check_heartbeats();
+    }
+}
@@ -0,0 +1,100 @@
+use chat_protocol::HistoryEntry;
+use chrono::Local;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const MAX_CACHED: usize = 500;
+
+pub fn entry(nick: &str, text: &str) -> HistoryEntry {
+    HistoryEntry {
+        timestamp: Local::now().timestamp(),
+        from: nick.to_string(),
+        text: text.to_string(),
+    }
+}
+
+fn to_line(entry: &HistoryEntry) -> String {
+    format!("{}\t{}\t{}\n", entry.timestamp, entry.from, entry.text)
+}
+
+fn from_line(line: &str) -> Option<HistoryEntry> {
+    let mut parts = line.splitn(3, '\t');
+    let timestamp = parts.next()?.parse().ok()?;
+    let from = parts.next()?.to_string();
+    let text = parts.next()?.to_string();
+    Some(HistoryEntry { timestamp, from, text })
+}
+
+// One append-only log file per room, with the tail of each room kept in memory.
+pub struct History {
+    dir: PathBuf,
+    rooms: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+impl History {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(History {
+            dir,
+            rooms: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn append(&self, room: &str, entry: HistoryEntry) -> io::Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let cached = self.load(&mut rooms, room)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(room))?;
+        file.write_all(to_line(&entry).as_bytes())?;
+
+        cached.push_back(entry);
+        if cached.len() > MAX_CACHED {
+            cached.pop_front();
+        }
+        Ok(())
+    }
+
+    pub fn recent(&self, room: &str, n: usize) -> io::Result<Vec<HistoryEntry>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let cached = self.load(&mut rooms, room)?;
+        let skip = cached.len().saturating_sub(n);
+        Ok(cached.iter().skip(skip).cloned().collect())
+    }
+
+    fn load<'a>(
+        &self,
+        rooms: &'a mut HashMap<String, VecDeque<HistoryEntry>>,
+        room: &str,
+    ) -> io::Result<&'a mut VecDeque<HistoryEntry>> {
+        if !rooms.contains_key(room) {
+            let mut entries = VecDeque::new();
+            match File::open(self.log_path(room)) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        if let Some(entry) = from_line(&line?) {
+                            entries.push_back(entry);
+                            if entries.len() > MAX_CACHED {
+                                entries.pop_front();
+                            }
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            rooms.insert(room.to_string(), entries);
+        }
+        Ok(rooms.get_mut(room).unwrap())
+    }
+
+    fn log_path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", room))
+    }
+}
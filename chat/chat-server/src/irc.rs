@@ -0,0 +1,557 @@
+use crate::server::{Outbox, Outgoing, Server, Traffic};
+use chat_protocol::{ClientMessage, ErrorCode, ServerMessage};
+use chrono::{Local, TimeZone};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SERVER_NAME: &str = "chat";
+// Like the WebSocket and TLS transports, one thread per connection alternates
+// between reading with a short timeout and flushing the client's outbox.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+// Nicknames per 353 (RPL_NAMREPLY) line, which keeps it well under 512 bytes.
+const NAMES_PER_LINE: usize = 12;
+
+pub fn listen(addr: &str, server: Arc<Server>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("IRC gateway listening on {}", addr);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let id = server.next_id();
+                println!("New IRC client connected #{}", id);
+
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    handle_irc_client(stream, id, server);
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting IRC client: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// `[:prefix] COMMAND param param :trailing param`, with the prefix dropped.
+struct Command {
+    name: String,
+    params: Vec<String>,
+}
+
+fn parse_command(line: &str) -> Option<Command> {
+    let mut rest = line.trim_start();
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    let name = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_string).collect();
+    params.extend(trailing.map(str::to_string));
+    Some(Command { name, params })
+}
+
+fn room_of(channel: &str) -> String {
+    channel.trim_start_matches('#').to_string()
+}
+
+fn channel_of(room: &str) -> String {
+    format!("#{}", room)
+}
+
+fn prefix(nick: &str) -> String {
+    format!("{}!{}@{}", nick, nick, SERVER_NAME)
+}
+
+// IRC has no timestamps, so anything older than a minute (offline messages,
+// history) is prefixed with when it was sent.
+fn stamp(timestamp: i64) -> String {
+    if Local::now().timestamp() - timestamp < 60 {
+        return String::new();
+    }
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|t| t.format("[%Y-%m-%d %H:%M] ").to_string())
+        .unwrap_or_default()
+}
+
+enum Input {
+    Line(String),
+    TooLong,
+}
+
+struct LineReader {
+    pending: Vec<u8>,
+    discarding: bool,
+    max_line_len: usize,
+    // Lines read along with the end of registration, returned by the next read.
+    leftover: Vec<Input>,
+}
+
+impl LineReader {
+    // Returns the complete lines read within one poll interval, or None on EOF.
+    fn read(&mut self, stream: &mut TcpStream, traffic: &Traffic) -> io::Result<Option<Vec<Input>>> {
+        if !self.leftover.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.leftover)));
+        }
+        let mut buffer = [0u8; 4096];
+        let n = match stream.read(&mut buffer) {
+            Ok(0) => return Ok(None),
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(Some(Vec::new())),
+            Err(e) => return Err(e),
+        };
+
+        traffic.add_received(n);
+        let mut lines = Vec::new();
+        self.pending.extend_from_slice(&buffer[..n]);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            if std::mem::take(&mut self.discarding) {
+                continue;
+            }
+            if line.len() > self.max_line_len + 1 {
+                lines.push(Input::TooLong);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+            lines.push(Input::Line(line));
+        }
+        if self.pending.len() > self.max_line_len {
+            if !self.discarding {
+                lines.push(Input::TooLong);
+                self.discarding = true;
+            }
+            self.pending.clear();
+        }
+        Ok(Some(lines))
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    messages: Receiver<Outgoing>,
+    traffic: Arc<Traffic>,
+    nick: String,
+    // The command and target (nick or #channel) last passed to the server,
+    // which its error replies are translated against.
+    command: &'static str,
+    target: String,
+}
+
+impl Connection {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\r\n", line);
+        self.stream.write_all(line.as_bytes())?;
+        self.traffic.add_sent(line.len());
+        Ok(())
+    }
+
+    fn numeric(&mut self, code: &str, params: &str) -> io::Result<()> {
+        let line = format!(":{} {} {} {}", SERVER_NAME, code, self.nick, params);
+        self.send(&line)
+    }
+
+    fn notice(&mut self, target: &str, text: &str) -> io::Result<()> {
+        self.send(&format!(":{} NOTICE {} :{}", SERVER_NAME, target, text))
+    }
+
+    fn welcome(&mut self) -> io::Result<()> {
+        let nick = self.nick.clone();
+        self.numeric("001", &format!(":Welcome to the chat server, {}", prefix(&nick)))?;
+        self.numeric("002", &format!(":Your host is {}", SERVER_NAME))?;
+        self.numeric("003", ":This server speaks a subset of IRC")?;
+        self.numeric("004", &format!("{} chat-server o o", SERVER_NAME))?;
+        self.numeric("422", ":MOTD File is missing")
+    }
+
+    fn names(&mut self, server: &Server, channel: &str) -> io::Result<()> {
+        let members = server.room_members(&room_of(channel));
+        for chunk in members.chunks(NAMES_PER_LINE) {
+            self.numeric("353", &format!("= {} :{}", channel, chunk.join(" ")))?;
+        }
+        self.numeric("366", &format!("{} :End of /NAMES list", channel))
+    }
+
+    // Writes a server message as IRC lines.
+    fn translate(&mut self, message: ServerMessage, server: &Server) -> io::Result<()> {
+        match message {
+            ServerMessage::Welcome { nick, .. } => {
+                self.nick = nick;
+                Ok(())
+            }
+            ServerMessage::Chat { room, from, text, timestamp } => {
+                // IRC clients show their own messages without an echo.
+                if from == self.nick {
+                    return Ok(());
+                }
+                self.send(&format!(":{} PRIVMSG {} :{}{}", prefix(&from), channel_of(&room), stamp(timestamp), text))
+            }
+            ServerMessage::Join { room, nick } => {
+                let channel = channel_of(&room);
+                self.send(&format!(":{} JOIN {}", prefix(&nick), channel))?;
+                if nick == self.nick {
+                    self.names(server, &channel)?;
+                }
+                Ok(())
+            }
+            ServerMessage::Leave { room, nick } => self.send(&format!(":{} PART {}", prefix(&nick), channel_of(&room))),
+            ServerMessage::Nick { old, new } => {
+                self.send(&format!(":{} NICK :{}", prefix(&old), new))?;
+                if old == self.nick {
+                    self.nick = new;
+                }
+                Ok(())
+            }
+            ServerMessage::Dm { from, to, text, timestamp, encrypted } => {
+                if from == self.nick {
+                    return Ok(());
+                }
+                if encrypted {
+                    let nick = self.nick.clone();
+                    return self.notice(&nick, &format!("{} sent you an encrypted message, which only chat-client can read", from));
+                }
+                self.send(&format!(":{} PRIVMSG {} :{}{}", prefix(&from), to, stamp(timestamp), text))
+            }
+            ServerMessage::History { room, messages } => {
+                let channel = channel_of(&room);
+                for entry in messages {
+                    let time = Local
+                        .timestamp_opt(entry.timestamp, 0)
+                        .single()
+                        .map(|t| t.format("%H:%M").to_string())
+                        .unwrap_or_default();
+                    self.notice(&channel, &format!("[{}] <{}> {}", time, entry.from, entry.text))?;
+                }
+                Ok(())
+            }
+            ServerMessage::System { text } => {
+                let nick = self.nick.clone();
+                self.notice(&nick, &text)
+            }
+            ServerMessage::Error { code, message } => self.error(code, &message),
+            ServerMessage::Ping { token } => self.send(&format!("PING :{}", token)),
+            // IRC clients can't download files, but they should know one was shared.
+            ServerMessage::File { room, from, file, .. } => self.notice(
+                &channel_of(&room),
+                &format!("{} shared {} ({} bytes, SHA-256 {})", from, file.name, file.size, file.sha256),
+            ),
+            // NAMES is sent along with the client's own JOIN instead.
+            ServerMessage::Ack { .. }
+            | ServerMessage::Members { .. }
+            | ServerMessage::Key { .. }
+            | ServerMessage::FileStart { .. }
+            | ServerMessage::FileChunk { .. }
+            | ServerMessage::FileEnd { .. } => Ok(()),
+        }
+    }
+
+    fn error(&mut self, code: ErrorCode, message: &str) -> io::Result<()> {
+        let target = self.target.clone();
+        match code {
+            ErrorCode::NickInUse | ErrorCode::NickReserved => self.numeric("433", &format!("{} :{}", target, message)),
+            ErrorCode::InvalidName if self.command == "nick" => self.numeric("432", &format!("{} :{}", target, message)),
+            ErrorCode::InvalidName if target.starts_with('#') => self.numeric("403", &format!("{} :{}", target, message)),
+            ErrorCode::NoSuchUser => self.numeric("401", &format!("{} :{}", target, message)),
+            ErrorCode::NotInRoom => self.numeric("442", &format!("{} :{}", target, message)),
+            ErrorCode::Muted if target.starts_with('#') => self.numeric("404", &format!("{} :{}", target, message)),
+            ErrorCode::Unauthenticated => self.numeric("451", &format!(":{}", message)),
+            ErrorCode::PermissionDenied => self.numeric("481", &format!(":{}", message)),
+            _ => {
+                let nick = self.nick.clone();
+                self.notice(&nick, message)
+            }
+        }
+    }
+
+    // Writes out everything queued for the client. Returns false once the
+    // server closed the connection.
+    fn flush(&mut self, server: &Server) -> io::Result<bool> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(outgoing) => match outgoing.into_messages() {
+                    Some(messages) => {
+                        for message in messages {
+                            self.translate(message, server)?;
+                        }
+                    }
+                    None => return Ok(false),
+                },
+                Err(TryRecvError::Disconnected) => return Ok(false),
+                Err(TryRecvError::Empty) => return Ok(true),
+            }
+        }
+    }
+
+    // Passes a message on to the server and writes its replies right away,
+    // while it is still known which nick or channel an error is about.
+    fn submit(&mut self, server: &Server, id: usize, target: &str, message: ClientMessage) -> io::Result<bool> {
+        self.command = message.kind();
+        self.target = target.to_string();
+        let keep_going = server.handle_client_message(id, message);
+        Ok(self.flush(server)? && keep_going)
+    }
+
+    fn handle_command(&mut self, server: &Server, id: usize, command: Command) -> io::Result<bool> {
+        let params = &command.params;
+        let required = match command.name.as_str() {
+            "NICK" | "JOIN" | "PART" | "PING" | "NAMES" | "MODE" | "TOPIC" => 1,
+            "PRIVMSG" => 2,
+            _ => 0,
+        };
+        if params.len() < required {
+            return self.numeric("461", &format!("{} :Not enough parameters", command.name)).map(|_| true);
+        }
+
+        match command.name.as_str() {
+            "NICK" => self.submit(server, id, &params[0], ClientMessage::Nick { nick: params[0].clone() }),
+            "USER" | "PASS" => self.numeric("462", ":You may not reregister").map(|_| true),
+            "JOIN" => {
+                for channel in params[0].split(',') {
+                    if !self.submit(server, id, channel, ClientMessage::Join { room: room_of(channel) })? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            "PART" => {
+                for channel in params[0].split(',') {
+                    if !self.submit(server, id, channel, ClientMessage::Leave { room: room_of(channel) })? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            "PRIVMSG" => {
+                let (target, text) = (&params[0], &params[1]);
+                // CTCP: /me becomes an emote, anything else (VERSION, PING...) is ignored.
+                let text = match text.strip_prefix('\u{1}') {
+                    Some(ctcp) => match ctcp.trim_end_matches('\u{1}').strip_prefix("ACTION ") {
+                        Some(action) => format!("* {} {}", self.nick, action),
+                        None => return Ok(true),
+                    },
+                    None => text.clone(),
+                };
+                let message = if target.starts_with('#') {
+                    ClientMessage::Chat { room: room_of(target), text }
+                } else {
+                    ClientMessage::Dm { to: target.clone(), text, encrypted: false }
+                };
+                self.submit(server, id, target, message)
+            }
+            // Notices must never trigger replies, and the server has nothing to map them to.
+            "NOTICE" => Ok(true),
+            "PING" => self.send(&format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, params[0])).map(|_| true),
+            "PONG" => {
+                let token = params.last().and_then(|token| token.parse().ok()).unwrap_or_default();
+                self.submit(server, id, "", ClientMessage::Pong { token })
+            }
+            "NAMES" => {
+                for channel in params[0].split(',') {
+                    self.names(server, channel)?;
+                }
+                Ok(true)
+            }
+            "MODE" if params[0].starts_with('#') => self.numeric("324", &format!("{} +", params[0])).map(|_| true),
+            "MODE" => self.numeric("221", "+").map(|_| true),
+            "WHO" => {
+                let mask = params.first().cloned().unwrap_or_else(|| "*".to_string());
+                self.numeric("315", &format!("{} :End of WHO list", mask)).map(|_| true)
+            }
+            "TOPIC" => self.numeric("331", &format!("{} :No topic is set", params[0])).map(|_| true),
+            "MOTD" => self.numeric("422", ":MOTD File is missing").map(|_| true),
+            "CAP" => self.cap(params).map(|_| true),
+            "QUIT" => {
+                self.submit(server, id, "", ClientMessage::Quit)?;
+                Ok(false)
+            }
+            _ => self.numeric("421", &format!("{} :Unknown command", command.name)).map(|_| true),
+        }
+    }
+
+    // No capabilities are offered, but answering CAP LS keeps clients that
+    // negotiate them from waiting.
+    fn cap(&mut self, params: &[String]) -> io::Result<()> {
+        match params.first().map(|sub| sub.to_ascii_uppercase()).as_deref() {
+            Some("LS") => self.send(&format!(":{} CAP * LS :", SERVER_NAME)),
+            Some("LIST") => self.send(&format!(":{} CAP * LIST :", SERVER_NAME)),
+            Some("REQ") => {
+                let requested = params.get(1).cloned().unwrap_or_default();
+                self.send(&format!(":{} CAP * NAK :{}", SERVER_NAME, requested))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Waits for NICK and USER (and an optional PASS, which logs in to the
+// account named by NICK). Returns None if the client quit or never finished.
+fn register(conn: &mut Connection, reader: &mut LineReader) -> io::Result<Option<(String, Option<String>)>> {
+    let started = Instant::now();
+    let (mut nick, mut user, mut password) = (None, false, None);
+
+    while started.elapsed() < REGISTRATION_TIMEOUT {
+        let lines = match reader.read(&mut conn.stream, &conn.traffic)? {
+            Some(lines) => lines,
+            None => return Ok(None),
+        };
+        let mut lines = lines.into_iter();
+        while let Some(input) = lines.next() {
+            let command = match input {
+                Input::Line(line) => match parse_command(&line) {
+                    Some(command) => command,
+                    None => continue,
+                },
+                Input::TooLong => continue,
+            };
+            match (command.name.as_str(), command.params.first()) {
+                ("NICK", Some(name)) => nick = Some(name.clone()),
+                ("USER", Some(_)) => user = true,
+                ("PASS", Some(pass)) => password = Some(pass.clone()),
+                ("PING", Some(token)) => conn.send(&format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token))?,
+                ("CAP", _) => conn.cap(&command.params)?,
+                ("QUIT", _) => return Ok(None),
+                ("NICK" | "USER" | "PASS" | "PING", None) => {
+                    conn.numeric("461", &format!("{} :Not enough parameters", command.name))?
+                }
+                _ => conn.numeric("451", ":You have not registered")?,
+            }
+            // Clients often send their first JOIN right behind NICK and USER.
+            if let (Some(nick), true) = (&nick, user) {
+                reader.leftover = lines.collect();
+                return Ok(Some((nick.clone(), password)));
+            }
+        }
+    }
+
+    conn.send("ERROR :Registration timed out")?;
+    Ok(None)
+}
+
+fn handle_irc_client(stream: TcpStream, id: usize, server: Arc<Server>) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let configured = stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .and_then(|_| stream.set_write_timeout(Some(server.heartbeat_config().timeout)));
+    if let Err(e) = configured {
+        eprintln!("Error configuring IRC client #{}: {}", id, e);
+        return;
+    }
+
+    let (outbox, messages) = mpsc::channel();
+    let mut conn = Connection {
+        stream,
+        messages,
+        traffic: Arc::new(Traffic::default()),
+        nick: "*".to_string(),
+        command: "",
+        target: String::new(),
+    };
+    let mut reader = LineReader {
+        pending: Vec::new(),
+        discarding: false,
+        max_line_len: server.max_line_len(),
+        leftover: Vec::new(),
+    };
+    if let Err(e) = run(&mut conn, &mut reader, id, addr, outbox, &server) {
+        eprintln!("Error talking to IRC client #{}: {}", id, e);
+    }
+    server.remove_client(id);
+}
+
+fn run(
+    conn: &mut Connection,
+    reader: &mut LineReader,
+    id: usize,
+    addr: SocketAddr,
+    outbox: Outbox,
+    server: &Server,
+) -> io::Result<()> {
+    let (nick, password) = match register(conn, reader)? {
+        Some(registration) => registration,
+        None => return Ok(()),
+    };
+
+    server.add_client(id, addr, outbox, Arc::clone(&conn.traffic));
+    let first = match password {
+        Some(password) => ClientMessage::Login { nick: nick.clone(), password },
+        None => ClientMessage::Nick { nick: nick.clone() },
+    };
+    conn.command = first.kind();
+    conn.target = nick;
+    server.handle_client_message(id, first);
+
+    // The server greets every client as a guest and may put it in the lobby
+    // before the nick above is applied. IRC clients expect 001 first, under
+    // the nick they will keep, so fold the guest name into the final one.
+    let queued: Vec<Outgoing> = conn.messages.try_iter().collect();
+    let guest = queued.iter().find_map(|outgoing| match outgoing {
+        Outgoing::Message(ServerMessage::Welcome { nick, .. }) => Some(nick.clone()),
+        _ => None,
+    });
+    if let Some(guest) = &guest {
+        conn.nick = queued.iter().fold(guest.clone(), |current, outgoing| match outgoing {
+            Outgoing::Message(ServerMessage::Nick { old, new }) if *old == current => new.clone(),
+            _ => current,
+        });
+        conn.welcome()?;
+    }
+    for outgoing in queued {
+        let message = match outgoing {
+            Outgoing::Message(message) => message,
+            // File transfers have no IRC counterpart.
+            Outgoing::Download(_) => continue,
+            Outgoing::Close => return conn.send("ERROR :Closing link"),
+        };
+        match message {
+            ServerMessage::Welcome { .. } => {}
+            ServerMessage::Nick { old, .. } if Some(&old) == guest.as_ref() => {}
+            ServerMessage::Join { room, nick } if Some(&nick) == guest.as_ref() => {
+                let nick = conn.nick.clone();
+                conn.translate(ServerMessage::Join { room, nick }, server)?;
+            }
+            message => conn.translate(message, server)?,
+        }
+    }
+
+    loop {
+        if !conn.flush(server)? {
+            return conn.send("ERROR :Closing link");
+        }
+
+        let lines = match reader.read(&mut conn.stream, &conn.traffic)? {
+            Some(lines) => lines,
+            None => return Ok(()),
+        };
+        for input in lines {
+            match input {
+                Input::Line(line) => {
+                    server.mark_seen(id);
+                    let command = match parse_command(&line) {
+                        Some(command) => command,
+                        None => continue,
+                    };
+                    if !conn.handle_command(server, id, command)? {
+                        return conn.send("ERROR :Closing link");
+                    }
+                }
+                Input::TooLong => server.line_too_long(id),
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+pub mod accounts;
+pub mod admin;
+pub mod federation;
+pub mod files;
+pub mod flood;
+pub mod heartbeat;
+pub mod history;
+pub mod irc;
+pub mod mailbox;
+pub mod moderation;
+pub mod server;
+pub mod tcp;
+pub mod tls;
+pub mod websocket;
+
+use rustls::ServerConfig;
+use server::Server;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+
+// Accepts clients on `listener`, each on its own thread, speaking TLS when
+// there is a config for it.
+pub fn serve(listener: TcpListener, server: Arc<Server>, tls_config: Option<Arc<ServerConfig>>) {
+    for stream in listener.incoming() {
+      match stream {
+        Ok(stream) => {
+            let client_id = server.next_id();
+            println!("New client connected #{}", client_id);
+
+            let server = Arc::clone(&server);
+            let tls_config = tls_config.clone();
+            thread::spawn(move || match tls_config {
+                Some(config) => tls::handle_tls_client(stream, client_id, server, config),
+                None => tcp::handle_client(stream, client_id, server),
+            });
+        }
+        Err(e) => {
+            eprintln!("Error accepting client: {}", e);
+        }
+      }
+    }
+}
@@ -0,0 +1,71 @@
+use chat_protocol::HistoryEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Oldest messages are dropped once an account has this many waiting.
+const MAX_PENDING: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+pub struct Letter {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    // Sealed by the sender's client; the server can't read it.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+// Direct messages for registered accounts that were offline when they were
+// sent, kept in a JSON file until the account logs in again.
+pub struct Mailbox {
+    path: PathBuf,
+    pending: Mutex<HashMap<String, Vec<Letter>>>,
+}
+
+impl Mailbox {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let pending = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Mailbox {
+            path,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    pub fn store(&self, account: &str, letter: Letter) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let messages = pending.entry(account.to_string()).or_default();
+        messages.push(letter);
+        if messages.len() > MAX_PENDING {
+            messages.remove(0);
+        }
+        self.save(&pending)
+    }
+
+    // Removes and returns everything waiting for `account`, oldest first.
+    pub fn take(&self, account: &str) -> io::Result<Vec<Letter>> {
+        let mut pending = self.pending.lock().unwrap();
+        let messages = match pending.remove(account) {
+            Some(messages) => messages,
+            None => return Ok(Vec::new()),
+        };
+        if let Err(e) = self.save(&pending) {
+            pending.insert(account.to_string(), messages);
+            return Err(e);
+        }
+        Ok(messages)
+    }
+
+    fn save(&self, pending: &HashMap<String, Vec<Letter>>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(pending)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -1,49 +1,220 @@
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use chat_server::accounts::Accounts;
+use chat_server::files::FileStore;
+use chat_server::flood::FloodConfig;
+use chat_server::heartbeat::{self, HeartbeatConfig};
+use chat_server::history::History;
+use chat_server::mailbox::Mailbox;
+use chat_server::moderation::Moderation;
+use chat_server::server::{Config, Server};
+use chat_server::{admin, federation, irc, tls, websocket};
+use clap::Parser;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-type ClientMap = Arc<Mutex<HashMap<usize, TcpStream>>>;
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: String,
 
-fn handle_client(mut stream: TcpStream, id: usize, clients: ClientMap) {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut line = String::new();
+    // Accept browser clients over WebSocket on this address, e.g. 127.0.0.1:8081.
+    #[arg(long)]
+    ws_addr: Option<String>,
 
-    let welcome =  format!("Welcome to the chat server #{}\n", id);
-    stream.write_all(welcome.as_bytes()).unwrap();
-}
+    // Name this server goes by on linked servers; must differ between them.
+    #[arg(long, default_value = "chat")]
+    server_name: String,
 
-fn broadcast_message() {
-}
+    // Accept links from other chat servers on this address.
+    #[arg(long)]
+    link_addr: Option<String>,
 
+    // Link to another server's --link-addr, reconnecting if the link drops (repeatable).
+    #[arg(long = "peer")]
+    peers: Vec<String>,
 
-fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    println!("Server listening on port 8080");
+    // File holding the secret every linked server must share; required with
+    // --link-addr or --peer.
+    #[arg(long)]
+    link_secret_file: Option<PathBuf>,
 
-    let clients: ClientMap = Arc::new(Mutex::new(HashMap::new()));
-    let mut client_id = 0;
+    // Accept IRC clients on this address, e.g. 127.0.0.1:6667.
+    #[arg(long)]
+    irc_addr: Option<String>,
 
-    for stream in listener.incoming() {
-      match stream {
-        Ok(stream) => {
-            println!("New client connected #{}", client_id);
+    // Unix socket for the admin interface (client list, stats, notices, shutdown).
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
 
-            let clients = Arc::clone(&clients);
-            clients.lock().unwrap().insert(client_id, stream.try_clone()?);
+    #[arg(long, default_value = "history")]
+    history_dir: PathBuf,
 
-            thread::spawn(move || {
-                handle_client(stream, client_id, clients);
-            });
+    #[arg(long, default_value = "accounts.json")]
+    accounts_file: PathBuf,
 
-            client_id += 1;
-        }
-        Err(e) => {
-            eprintln!("Error accepting client: {}", e);
+    // Reject everything but register/login until the client has authenticated.
+    #[arg(long)]
+    require_auth: bool,
+
+    // Serve --addr over TLS. A self-signed certificate is generated when
+    // neither --tls-cert nor --tls-key exist yet.
+    #[arg(long)]
+    tls: bool,
+
+    #[arg(long, default_value = "cert.pem")]
+    tls_cert: PathBuf,
+
+    #[arg(long, default_value = "key.pem")]
+    tls_key: PathBuf,
+
+    // Operators, IP and nickname bans are persisted here.
+    #[arg(long, default_value = "moderation.json")]
+    moderation_file: PathBuf,
+
+    // Direct messages for offline registered users wait here until they log in.
+    #[arg(long, default_value = "mailbox.json")]
+    mailbox_file: PathBuf,
+
+    // Files shared in rooms are kept here.
+    #[arg(long, default_value = "files")]
+    files_dir: PathBuf,
+
+    // Largest file that can be shared, in bytes.
+    #[arg(long, default_value_t = 10 * 1024 * 1024)]
+    max_upload_size: u64,
+
+    // Grant operator status to a registered account (repeatable).
+    #[arg(long = "op")]
+    operators: Vec<String>,
+
+    // Sustained messages per second allowed per client.
+    #[arg(long, default_value_t = 2.0)]
+    rate_limit: f64,
+
+    // Messages a client may send in a burst before --rate-limit kicks in.
+    #[arg(long, default_value_t = 10.0)]
+    burst: f64,
+
+    // Longest accepted line (or WebSocket message) in bytes.
+    #[arg(long, default_value_t = 4096)]
+    max_line_len: usize,
+
+    // Seconds of silence after which a client is pinged.
+    #[arg(long, default_value_t = 30)]
+    heartbeat_interval: u64,
+
+    // Seconds of silence after which a client is considered dead and dropped.
+    #[arg(long, default_value_t = 90)]
+    heartbeat_timeout: u64,
+}
+
+fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    if cli.heartbeat_timeout <= cli.heartbeat_interval {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--heartbeat-timeout must be longer than --heartbeat-interval",
+        ));
+    }
+
+    let federated = cli.link_addr.is_some() || !cli.peers.is_empty();
+    let link_secret = match &cli.link_secret_file {
+        Some(path) => fs::read_to_string(path)?.trim().to_string(),
+        None if federated => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--link-addr and --peer need --link-secret-file"));
         }
-      }
+        None => String::new(),
+    };
+    if federated && link_secret.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the link secret file is empty"));
+    }
+
+    let tls_config = if cli.tls {
+        Some(tls::load_config(&cli.tls_cert, &cli.tls_key)?)
+    } else {
+        None
+    };
+
+    let listener = TcpListener::bind(&cli.addr)?;
+    println!("Server listening on {}{}", cli.addr, if cli.tls { " (TLS)" } else { "" });
+
+    let config = Config {
+        require_auth: cli.require_auth,
+        flood: FloodConfig {
+            rate: cli.rate_limit,
+            burst: cli.burst,
+            max_line_len: cli.max_line_len,
+        },
+        heartbeat: HeartbeatConfig {
+            interval: Duration::from_secs(cli.heartbeat_interval),
+            timeout: Duration::from_secs(cli.heartbeat_timeout),
+        },
+        server_name: cli.server_name,
+        link_secret,
+    };
+    let moderation = Moderation::open(&cli.moderation_file)?;
+    for operator in &cli.operators {
+        moderation.set_operator(operator, true)?;
+    }
+    let server = Arc::new(Server::new(
+        config,
+        History::open(&cli.history_dir)?,
+        Accounts::open(&cli.accounts_file)?,
+        moderation,
+        Mailbox::open(&cli.mailbox_file)?,
+        FileStore::open(&cli.files_dir, cli.max_upload_size)?,
+    ));
+
+    {
+        let server = Arc::clone(&server);
+        thread::spawn(move || heartbeat::run(server));
+    }
+
+    if let Some(ws_addr) = cli.ws_addr {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = websocket::listen(&ws_addr, server) {
+                eprintln!("WebSocket gateway failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(link_addr) = cli.link_addr {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = federation::listen(&link_addr, server) {
+                eprintln!("Server link listener failed: {}", e);
+            }
+        });
+    }
+    for peer in cli.peers {
+        let server = Arc::clone(&server);
+        thread::spawn(move || federation::connect(peer, server));
+    }
+
+    if let Some(irc_addr) = cli.irc_addr {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = irc::listen(&irc_addr, server) {
+                eprintln!("IRC gateway failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(admin_socket) = cli.admin_socket {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = admin::listen(&admin_socket, server) {
+                eprintln!("Admin socket failed: {}", e);
+            }
+        });
     }
 
+    chat_server::serve(listener, server, tls_config);
     Ok(())
 }
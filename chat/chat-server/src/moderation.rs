@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    operators: BTreeSet<String>,
+    banned_nicks: BTreeSet<String>,
+    banned_ips: BTreeSet<IpAddr>,
+}
+
+// Who a mute applies to: a connection, and also the account if it's logged
+// in, so neither changing nick nor reconnecting gets rid of it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MuteTarget {
+    Client(usize),
+    Account(String),
+}
+
+// Operators and bans are persisted to a JSON file; mutes only live in memory
+// since they expire anyway.
+pub struct Moderation {
+    path: PathBuf,
+    state: Mutex<State>,
+    mutes: Mutex<HashMap<MuteTarget, Instant>>,
+}
+
+impl Moderation {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Moderation {
+            path,
+            state: Mutex::new(state),
+            mutes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_operator(&self, account: &str) -> bool {
+        self.state.lock().unwrap().operators.contains(account)
+    }
+
+    pub fn set_operator(&self, account: &str, operator: bool) -> io::Result<bool> {
+        self.update(|state| {
+            if operator {
+                state.operators.insert(account.to_string())
+            } else {
+                state.operators.remove(account)
+            }
+        })
+    }
+
+    pub fn is_nick_banned(&self, nick: &str) -> bool {
+        self.state.lock().unwrap().banned_nicks.contains(nick)
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.state.lock().unwrap().banned_ips.contains(&ip)
+    }
+
+    pub fn ban_nick(&self, nick: &str, banned: bool) -> io::Result<bool> {
+        self.update(|state| {
+            if banned {
+                state.banned_nicks.insert(nick.to_string())
+            } else {
+                state.banned_nicks.remove(nick)
+            }
+        })
+    }
+
+    pub fn ban_ip(&self, ip: IpAddr, banned: bool) -> io::Result<bool> {
+        self.update(|state| {
+            if banned {
+                state.banned_ips.insert(ip)
+            } else {
+                state.banned_ips.remove(&ip)
+            }
+        })
+    }
+
+    pub fn mute(&self, target: MuteTarget, duration: Duration) {
+        self.mutes.lock().unwrap().insert(target, Instant::now() + duration);
+    }
+
+    pub fn unmute(&self, target: &MuteTarget) -> bool {
+        self.mutes.lock().unwrap().remove(target).is_some()
+    }
+
+    // Remaining mute time for `target`, if any.
+    pub fn muted_for(&self, target: &MuteTarget) -> Option<Duration> {
+        let mut mutes = self.mutes.lock().unwrap();
+        let until = *mutes.get(target)?;
+        let now = Instant::now();
+        if until <= now {
+            mutes.remove(target);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    // Applies `change` and saves the state if it reports a modification.
+    fn update(&self, change: impl FnOnce(&mut State) -> bool) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if !change(&mut state) {
+            return Ok(false);
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&*state)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(true)
+    }
+}
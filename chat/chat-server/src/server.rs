@@ -0,0 +1,1253 @@
+use crate::accounts::{AccountError, Accounts};
+use crate::federation::{Event, Federation, Rooms};
+use crate::files::{self, Download, FileError, FileStore, Upload};
+use crate::flood::{FloodConfig, FloodState, Penalty};
+use crate::heartbeat::HeartbeatConfig;
+use crate::history::{self, History};
+use crate::mailbox::{Letter, Mailbox};
+use crate::moderation::{Moderation, MuteTarget};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chat_protocol::{decode, ClientMessage, ErrorCode, HistoryEntry, ServerMessage};
+use chrono::Local;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_ROOM: &str = "lobby";
+const REPLAY_ON_JOIN: usize = 20;
+const MAX_HISTORY_REQUEST: usize = 500;
+const MAX_NAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+
+pub enum Outgoing {
+    Message(ServerMessage),
+    // Read from disk as it is written out, so a whole file is never in memory.
+    Download(Box<Download>),
+    // Close the connection once everything queued before it has been written.
+    Close,
+}
+
+impl Outgoing {
+    // What to write out, in order, or None if the connection should be closed.
+    pub fn into_messages(self) -> Option<Box<dyn Iterator<Item = ServerMessage>>> {
+        match self {
+            Outgoing::Message(message) => Some(Box::new(std::iter::once(message))),
+            Outgoing::Download(download) => Some(Box::new(download)),
+            Outgoing::Close => None,
+        }
+    }
+}
+
+// Each transport (plain TCP, WebSocket, TLS) owns the receiving end of
+// `outbox` and takes care of encoding and writing the messages to its socket.
+pub type Outbox = Sender<Outgoing>;
+
+// Bytes read from and written to a client's socket. Transports count as they
+// go, without taking the client map's lock.
+#[derive(Default)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    fn absorb(&self, other: &Traffic) {
+        self.received.fetch_add(other.received(), Ordering::Relaxed);
+        self.sent.fetch_add(other.sent(), Ordering::Relaxed);
+    }
+}
+
+pub struct Client {
+    outbox: Outbox,
+    pub addr: SocketAddr,
+    pub nick: String,
+    pub account: Option<String>,
+    pub rooms: HashSet<String>,
+    flood: FloodState,
+    // When the client last sent anything, and when it was last pinged.
+    last_seen: Instant,
+    last_ping: Instant,
+    upload: Option<Upload>,
+    // Public key for encrypted DMs, if the client published one.
+    key: Option<String>,
+    traffic: Arc<Traffic>,
+    connected: Instant,
+    // Set once the server decided to drop the client; nothing it sends after that is handled.
+    closing: bool,
+}
+
+impl Client {
+    fn send(&self, message: &ServerMessage) -> Result<(), SendError<Outgoing>> {
+        self.outbox.send(Outgoing::Message(message.clone()))
+    }
+
+    fn close(&self) {
+        let _ = self.outbox.send(Outgoing::Close);
+    }
+}
+
+pub type ClientMap = Mutex<HashMap<usize, Client>>;
+
+// What the admin interface shows about a connected client.
+pub struct ClientInfo {
+    pub id: usize,
+    pub addr: SocketAddr,
+    pub nick: String,
+    pub account: Option<String>,
+    pub rooms: Vec<String>,
+    pub received: u64,
+    pub sent: u64,
+    pub connected: Duration,
+    pub idle: Duration,
+}
+
+pub struct Stats {
+    pub uptime: Duration,
+    pub clients: usize,
+    pub rooms: usize,
+    pub links: Vec<String>,
+    // Including clients that have disconnected since the start.
+    pub received: u64,
+    pub sent: u64,
+}
+
+type Reply = Result<(), ServerMessage>;
+
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_name(name: &str) -> Reply {
+    if is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(ServerMessage::error(
+            ErrorCode::InvalidName,
+            format!("Invalid name '{}': use letters, digits, '-' and '_'", name),
+        ))
+    }
+}
+
+// Guests are named guest<id> by the server, so nobody may pick such a name
+// for themselves or register it.
+fn is_guest_name(name: &str) -> bool {
+    name.strip_prefix("guest").is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+fn validate_chosen_nick(nick: &str) -> Reply {
+    validate_name(nick)?;
+    if is_guest_name(nick) {
+        return Err(ServerMessage::error(
+            ErrorCode::NickReserved,
+            format!("Nickname {} is reserved for guests", nick),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn is_valid_text(text: &str) -> bool {
+    !text.trim().is_empty() && !text.chars().any(|c| c.is_control())
+}
+
+fn validate_text(text: &str) -> Reply {
+    if !is_valid_text(text) {
+        Err(ServerMessage::error(ErrorCode::InvalidMessage, "Message text must be non-empty and free of control characters"))
+    } else {
+        Ok(())
+    }
+}
+
+// Reasons end up in system messages (and IRC notices), so they get the same
+// checks as chat text.
+fn format_reason(reason: Option<&str>) -> Result<String, ServerMessage> {
+    match reason {
+        Some(reason) => {
+            validate_text(reason)?;
+            Ok(format!(" ({})", reason))
+        }
+        None => Ok(String::new()),
+    }
+}
+
+#[derive(Default)]
+pub struct Config {
+    // Only register, login and quit are accepted until the client logs in.
+    pub require_auth: bool,
+    pub flood: FloodConfig,
+    pub heartbeat: HeartbeatConfig,
+    // Identifies this server to linked servers; its users appear there as nick@server_name.
+    pub server_name: String,
+    // Shared by linked servers, which prove they know it before their events are accepted.
+    pub link_secret: String,
+}
+
+fn file_error(e: FileError) -> ServerMessage {
+    match e {
+        FileError::TooLarge { .. } => ServerMessage::error(ErrorCode::FileTooLarge, e.to_string()),
+        FileError::Io(e) => {
+            eprintln!("Error storing upload: {}", e);
+            ServerMessage::error(ErrorCode::Internal, "Could not store the file")
+        }
+        e => ServerMessage::error(ErrorCode::TransferFailed, e.to_string()),
+    }
+}
+
+fn persist_error(e: std::io::Error) -> ServerMessage {
+    eprintln!("Error saving moderation state: {}", e);
+    ServerMessage::error(ErrorCode::Internal, "Could not save moderation state")
+}
+
+pub struct Server {
+    config: Config,
+    clients: ClientMap,
+    history: History,
+    accounts: Accounts,
+    moderation: Moderation,
+    mailbox: Mailbox,
+    files: FileStore,
+    federation: Federation,
+    next_id: AtomicUsize,
+    started: Instant,
+    shutting_down: AtomicBool,
+    // Traffic of clients that have disconnected.
+    past_traffic: Traffic,
+    // The last key each account published, so DMs to it can still be
+    // encrypted while it is offline. Kept in memory only.
+    account_keys: Mutex<HashMap<String, String>>,
+}
+
+impl Server {
+    pub fn new(
+        config: Config,
+        history: History,
+        accounts: Accounts,
+        moderation: Moderation,
+        mailbox: Mailbox,
+        files: FileStore,
+    ) -> Self {
+        Server {
+            federation: Federation::new(config.server_name.clone(), config.link_secret.clone()),
+            config,
+            clients: Mutex::new(HashMap::new()),
+            history,
+            accounts,
+            moderation,
+            mailbox,
+            files,
+            next_id: AtomicUsize::new(0),
+            started: Instant::now(),
+            shutting_down: AtomicBool::new(false),
+            past_traffic: Traffic::default(),
+            account_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn add_client(&self, id: usize, addr: SocketAddr, outbox: Outbox, traffic: Arc<Traffic>) {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            let _ = outbox.send(Outgoing::Message(ServerMessage::system("Server is shutting down")));
+            let _ = outbox.send(Outgoing::Close);
+            return;
+        }
+        if self.moderation.is_ip_banned(addr.ip()) {
+            println!("Rejected client #{} from banned address {}", id, addr.ip());
+            let _ = outbox.send(Outgoing::Message(ServerMessage::error(ErrorCode::Banned, "You are banned from this server")));
+            let _ = outbox.send(Outgoing::Close);
+            return;
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // Accounts from before guest names were reserved may still hold one.
+        let nick = std::iter::once(format!("guest{}", id))
+            .chain((1..).map(|n| format!("guest{}_{}", id, n)))
+            .find(|nick| !self.accounts.is_registered(nick) && clients.values().all(|client| &client.nick != nick))
+            .unwrap();
+        clients.insert(id, Client {
+            outbox,
+            addr,
+            nick: nick.clone(),
+            account: None,
+            rooms: HashSet::new(),
+            flood: FloodState::new(&self.config.flood),
+            last_seen: now,
+            last_ping: now,
+            upload: None,
+            key: None,
+            traffic,
+            connected: now,
+            closing: false,
+        });
+        drop(clients);
+
+        self.send_to(id, &ServerMessage::Welcome { id, nick });
+        if self.config.require_auth {
+            self.send_to(id, &ServerMessage::system("Authentication required: /register or /login first"));
+        } else if let Err(reply) = self.join(id, DEFAULT_ROOM) {
+            self.send_to(id, &reply);
+        }
+    }
+
+    pub fn remove_client(&self, id: usize) {
+        let removed = self.clients.lock().unwrap().remove(&id);
+        self.moderation.unmute(&MuteTarget::Client(id));
+        if let Some(client) = removed {
+            println!("Client #{} ({}) disconnected", id, client.nick);
+            self.past_traffic.absorb(&client.traffic);
+            for room in &client.rooms {
+                self.broadcast(room, &ServerMessage::Leave { room: room.clone(), nick: client.nick.clone() });
+                self.federation.publish(Event::Leave { room: room.clone(), nick: client.nick.clone() });
+            }
+        }
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let now = Instant::now();
+        let mut clients: Vec<ClientInfo> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, client)| {
+                let mut rooms: Vec<String> = client.rooms.iter().cloned().collect();
+                rooms.sort();
+                ClientInfo {
+                    id,
+                    addr: client.addr,
+                    nick: client.nick.clone(),
+                    account: client.account.clone(),
+                    rooms,
+                    received: client.traffic.received(),
+                    sent: client.traffic.sent(),
+                    connected: now.duration_since(client.connected),
+                    idle: now.duration_since(client.last_seen),
+                }
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    pub fn stats(&self) -> Stats {
+        let (clients, rooms, received, sent) = {
+            let clients = self.clients.lock().unwrap();
+            let rooms: HashSet<&String> = clients.values().flat_map(|client| &client.rooms).collect();
+            let received: u64 = clients.values().map(|client| client.traffic.received()).sum();
+            let sent: u64 = clients.values().map(|client| client.traffic.sent()).sum();
+            (clients.len(), rooms.len(), received, sent)
+        };
+        Stats {
+            uptime: self.started.elapsed(),
+            clients,
+            rooms,
+            links: self.federation.links(),
+            received: received + self.past_traffic.received(),
+            sent: sent + self.past_traffic.sent(),
+        }
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    // Sends a server notice to every connected client and returns how many there were.
+    pub fn notice(&self, text: &str) -> usize {
+        let message = ServerMessage::system(format!("Server notice: {}", text));
+        let clients = self.clients.lock().unwrap();
+        for client in clients.values() {
+            let _ = client.send(&message);
+        }
+        clients.len()
+    }
+
+    // Turns away new clients and closes every connection once what is queued
+    // for it has been written.
+    pub fn shutdown(&self, reason: Option<&str>) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let text = match reason {
+            Some(reason) => format!("Server is shutting down: {}", reason),
+            None => "Server is shutting down".to_string(),
+        };
+        let message = ServerMessage::system(text);
+        for client in self.clients.lock().unwrap().values_mut() {
+            let _ = client.send(&message);
+            client.close();
+            client.closing = true;
+        }
+    }
+
+    pub fn federation(&self) -> &Federation {
+        &self.federation
+    }
+
+    // Which local clients are in which room, for linked servers.
+    pub fn local_rooms(&self) -> Rooms {
+        let mut rooms = Rooms::new();
+        for client in self.clients.lock().unwrap().values() {
+            for room in &client.rooms {
+                rooms.entry(room.clone()).or_default().insert(client.nick.clone());
+            }
+        }
+        rooms
+    }
+
+    // Shows something that happened on a linked server to local clients in
+    // any of `rooms`, each of them getting it once.
+    pub fn deliver_remote(&self, rooms: &[String], message: &ServerMessage) {
+        if let ServerMessage::Chat { room, from, text, timestamp } = message {
+            let entry = HistoryEntry { timestamp: *timestamp, from: from.clone(), text: text.clone() };
+            if let Err(e) = self.history.append(room, entry) {
+                eprintln!("Error writing history for #{}: {}", room, e);
+            }
+        }
+        let clients = self.clients.lock().unwrap();
+        for (&id, client) in clients.iter() {
+            if !rooms.iter().any(|room| client.rooms.contains(room)) {
+                continue;
+            }
+            if let Err(e) = client.send(message) {
+                eprintln!("Error sending to client #{}: {}", id, e);
+            }
+        }
+    }
+
+    pub fn send_to(&self, id: usize, message: &ServerMessage) {
+        if let Some(client) = self.clients.lock().unwrap().get(&id) {
+            if let Err(e) = client.send(message) {
+                eprintln!("Error sending to client #{}: {}", id, e);
+            }
+        }
+    }
+
+    // Sends `message` and then closes the client's connection.
+    fn disconnect(&self, id: usize, message: &ServerMessage) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            let _ = client.send(message);
+            client.close();
+            client.closing = true;
+        }
+    }
+
+    pub fn broadcast(&self, room: &str, message: &ServerMessage) {
+        let clients = self.clients.lock().unwrap();
+        for (&id, client) in clients.iter() {
+            if !client.rooms.contains(room) {
+                continue;
+            }
+            if let Err(e) = client.send(message) {
+                eprintln!("Error sending to client #{}: {}", id, e);
+            }
+        }
+    }
+
+    pub fn max_line_len(&self) -> usize {
+        self.config.flood.max_line_len
+    }
+
+    // Called by transports for a line (or frame) longer than `max_line_len`,
+    // which is dropped instead of being handled.
+    pub fn line_too_long(&self, id: usize) {
+        self.mark_seen(id);
+        self.penalize(
+            id,
+            ErrorCode::MessageTooLong,
+            &format!("Messages are limited to {} bytes", self.config.flood.max_line_len),
+        );
+    }
+
+    fn penalize(&self, id: usize, code: ErrorCode, warning: &str) {
+        let (penalty, nick) = match self.clients.lock().unwrap().get_mut(&id) {
+            Some(client) => match client.flood.violation() {
+                Some(penalty) => (penalty, client.nick.clone()),
+                None => return,
+            },
+            None => return,
+        };
+
+        match penalty {
+            Penalty::Warn => {
+                self.send_to(id, &ServerMessage::error(code, format!("{}; keep it up and you will be muted", warning)));
+            }
+            Penalty::Mute(duration) => {
+                self.announce(id, &format!("{} was muted for {}s for flooding", nick, duration.as_secs()));
+                self.send_to(id, &ServerMessage::error(code, format!("{}; you are muted for {}s", warning, duration.as_secs())));
+            }
+            Penalty::Disconnect => {
+                println!("Disconnecting client #{} ({}) for flooding", id, nick);
+                self.announce(id, &format!("{} was disconnected for flooding", nick));
+                self.disconnect(id, &ServerMessage::error(code, "Disconnected for flooding"));
+            }
+        }
+    }
+
+    fn allow_message(&self, id: usize) -> bool {
+        let config = &self.config.flood;
+        self.clients
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .is_some_and(|client| client.flood.allow(config))
+    }
+
+    // Records that the client is alive; transports call this for traffic that
+    // never reaches handle_line, like protocol-level pings.
+    pub fn mark_seen(&self, id: usize) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.last_seen = Instant::now();
+        }
+    }
+
+    pub fn heartbeat_config(&self) -> HeartbeatConfig {
+        self.config.heartbeat
+    }
+
+    // Pings clients that have been quiet for the heartbeat interval and drops
+    // the ones that haven't sent anything, pong or otherwise, within the timeout.
+    pub fn check_heartbeats(&self) {
+        let config = self.config.heartbeat;
+        let now = Instant::now();
+        let token = Local::now().timestamp_millis() as u64;
+        let mut timed_out = Vec::new();
+        for (&id, client) in self.clients.lock().unwrap().iter_mut() {
+            let idle = now.duration_since(client.last_seen);
+            if idle >= config.timeout {
+                timed_out.push((id, client.nick.clone()));
+            } else if idle >= config.interval && now.duration_since(client.last_ping) >= config.interval {
+                let _ = client.send(&ServerMessage::Ping { token });
+                client.last_ping = now;
+            }
+        }
+
+        for (id, nick) in timed_out {
+            println!("Client #{} ({}) timed out", id, nick);
+            self.announce(id, &format!("{} timed out", nick));
+            self.send_to(id, &ServerMessage::system("Disconnected: ping timeout"));
+            // Dropping the outbox closes the connection even if the transport
+            // is stuck waiting on a peer that went away.
+            self.remove_client(id);
+        }
+    }
+
+    fn is_closing(&self, id: usize) -> bool {
+        self.clients.lock().unwrap().get(&id).is_none_or(|client| client.closing)
+    }
+
+    // Decodes one line (or WebSocket text frame) from a client and handles it.
+    // Returns false once the client asked to disconnect or is being dropped.
+    pub fn handle_line(&self, id: usize, line: &str) -> bool {
+        self.mark_seen(id);
+        if self.is_closing(id) {
+            return false;
+        }
+        if line.trim().is_empty() {
+            return true;
+        }
+        let message = decode::<ClientMessage>(line);
+        let exempt = message.as_ref().is_ok_and(|message| self.is_rate_exempt(id, message));
+        if !exempt && !self.check_rate(id) {
+            return true;
+        }
+        match message {
+            Ok(message) => self.handle_message(id, message),
+            Err(e) => {
+                self.send_to(id, &ServerMessage::error(e.code(), e.to_string()));
+                true
+            }
+        }
+    }
+
+    // Like handle_line, for transports that translate their own wire format
+    // (IRC) into client messages.
+    pub fn handle_client_message(&self, id: usize, message: ClientMessage) -> bool {
+        self.mark_seen(id);
+        if self.is_closing(id) {
+            return false;
+        }
+        if !self.is_rate_exempt(id, &message) && !self.check_rate(id) {
+            return true;
+        }
+        self.handle_message(id, message)
+    }
+
+    // Upload chunks arrive back to back and are bounded by the upload size
+    // instead of the message rate.
+    fn is_rate_exempt(&self, id: usize, message: &ClientMessage) -> bool {
+        matches!(message, ClientMessage::UploadChunk { .. })
+            && self.clients.lock().unwrap().get(&id).is_some_and(|client| client.upload.is_some())
+    }
+
+    fn check_rate(&self, id: usize) -> bool {
+        if self.allow_message(id) {
+            return true;
+        }
+        self.penalize(id, ErrorCode::RateLimited, "You are sending messages too fast");
+        false
+    }
+
+    fn handle_message(&self, id: usize, message: ClientMessage) -> bool {
+        let command = message.kind();
+        if self.config.require_auth
+            && !matches!(
+                message,
+                ClientMessage::Register { .. } | ClientMessage::Login { .. } | ClientMessage::Pong { .. } | ClientMessage::Quit
+            )
+            && !self.is_authenticated(id)
+        {
+            self.send_to(id, &ServerMessage::error(ErrorCode::Unauthenticated, "Log in with /login or /register first"));
+            return true;
+        }
+
+        let result = match message {
+            ClientMessage::Chat { room, text } => self.chat(id, &room, &text),
+            ClientMessage::Join { room } => self.join(id, &room),
+            ClientMessage::Leave { room } => self.leave(id, &room),
+            ClientMessage::Nick { nick } => self.nick(id, &nick),
+            ClientMessage::Dm { to, text, encrypted } => self.dm(id, &to, &text, encrypted),
+            ClientMessage::History { room, limit } => self.history(id, &room, limit),
+            ClientMessage::Register { nick, password } => self.register(id, &nick, &password),
+            ClientMessage::Login { nick, password } => self.login(id, &nick, &password),
+            ClientMessage::Kick { nick, reason } => self.kick(id, &nick, reason.as_deref()),
+            ClientMessage::Ban { target, reason } => self.ban(id, &target, reason.as_deref()),
+            ClientMessage::Unban { target } => self.unban(id, &target),
+            ClientMessage::Mute { nick, seconds } => self.mute(id, &nick, seconds),
+            ClientMessage::Unmute { nick } => self.unmute(id, &nick),
+            ClientMessage::Op { nick } => self.set_operator(id, &nick, true),
+            ClientMessage::Deop { nick } => self.set_operator(id, &nick, false),
+            ClientMessage::Upload { room, name, size } => self.upload(id, &room, &name, size),
+            ClientMessage::UploadChunk { data } => self.upload_chunk(id, &data),
+            ClientMessage::UploadEnd { sha256 } => self.upload_end(id, &sha256),
+            ClientMessage::Download { id: file } => self.download(id, &file),
+            ClientMessage::PublishKey { key } => self.publish_key(id, key),
+            ClientMessage::GetKey { nick } => self.get_key(id, &nick),
+            // A pong only matters as a sign of life, which handle_line already
+            // recorded, so unlike every other message it isn't acknowledged.
+            ClientMessage::Pong { .. } => return true,
+            ClientMessage::Quit => {
+                self.send_to(id, &ServerMessage::Ack { command: command.to_string() });
+                return false;
+            }
+        };
+
+        match result {
+            Ok(()) => self.send_to(id, &ServerMessage::Ack { command: command.to_string() }),
+            Err(reply) => self.send_to(id, &reply),
+        }
+        true
+    }
+
+    fn is_authenticated(&self, id: usize) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|client| client.account.is_some())
+    }
+
+    fn nick_of(&self, id: usize) -> Result<String, ServerMessage> {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|client| client.nick.clone())
+            .ok_or_else(|| ServerMessage::error(ErrorCode::Internal, "Unknown client"))
+    }
+
+    fn require_room(&self, id: usize, room: &str) -> Result<String, ServerMessage> {
+        match self.clients.lock().unwrap().get(&id) {
+            Some(client) if client.rooms.contains(room) => Ok(client.nick.clone()),
+            Some(_) => Err(ServerMessage::error(ErrorCode::NotInRoom, format!("You are not in #{}", room))),
+            None => Err(ServerMessage::error(ErrorCode::Internal, "Unknown client")),
+        }
+    }
+
+    // The connection and, once logged in, the account a mute may be attached to.
+    fn mute_targets(&self, id: usize) -> Vec<MuteTarget> {
+        let mut targets = vec![MuteTarget::Client(id)];
+        if let Some(account) = self.clients.lock().unwrap().get(&id).and_then(|client| client.account.clone()) {
+            targets.push(MuteTarget::Account(account));
+        }
+        targets
+    }
+
+    fn check_muted(&self, id: usize) -> Reply {
+        let flooded = self.clients.lock().unwrap().get(&id).and_then(|client| client.flood.muted_for());
+        let remaining = self
+            .mute_targets(id)
+            .iter()
+            .filter_map(|target| self.moderation.muted_for(target))
+            .chain(flooded)
+            .max();
+        match remaining {
+            Some(remaining) => Err(ServerMessage::error(
+                ErrorCode::Muted,
+                format!("You are muted for another {}s", remaining.as_secs() + 1),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn check_nick_banned(&self, nick: &str) -> Reply {
+        if self.moderation.is_nick_banned(nick) {
+            Err(ServerMessage::error(ErrorCode::Banned, format!("Nickname {} is banned", nick)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn chat(&self, id: usize, room: &str, text: &str) -> Reply {
+        validate_text(text)?;
+        let nick = self.require_room(id, room)?;
+        self.check_muted(id)?;
+        let entry = history::entry(&nick, text);
+        if let Err(e) = self.history.append(room, entry.clone()) {
+            eprintln!("Error writing history for #{}: {}", room, e);
+        }
+        self.broadcast(room, &ServerMessage::Chat {
+            room: room.to_string(),
+            from: entry.from.clone(),
+            text: entry.text.clone(),
+            timestamp: entry.timestamp,
+        });
+        self.federation.publish(Event::Chat {
+            room: room.to_string(),
+            from: entry.from,
+            text: entry.text,
+            timestamp: entry.timestamp,
+        });
+        Ok(())
+    }
+
+    fn join(&self, id: usize, room: &str) -> Reply {
+        validate_name(room)?;
+        let nick = {
+            let mut clients = self.clients.lock().unwrap();
+            let client = clients
+                .get_mut(&id)
+                .ok_or_else(|| ServerMessage::error(ErrorCode::Internal, "Unknown client"))?;
+            if !client.rooms.insert(room.to_string()) {
+                return Ok(());
+            }
+            let nick = client.nick.clone();
+            // Sent under the lock so no join or leave in the room can overtake it.
+            let members = ServerMessage::Members { room: room.to_string(), nicks: self.members(&clients, room) };
+            let _ = clients[&id].send(&members);
+            nick
+        };
+
+        self.broadcast(room, &ServerMessage::Join { room: room.to_string(), nick: nick.clone() });
+        self.federation.publish(Event::Join { room: room.to_string(), nick });
+        self.replay_history(id, room, REPLAY_ON_JOIN)
+    }
+
+    fn leave(&self, id: usize, room: &str) -> Reply {
+        let nick = self.require_room(id, room)?;
+        self.broadcast(room, &ServerMessage::Leave { room: room.to_string(), nick: nick.clone() });
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.rooms.remove(room);
+        }
+        self.federation.publish(Event::Leave { room: room.to_string(), nick });
+        Ok(())
+    }
+
+    fn nick(&self, id: usize, nick: &str) -> Reply {
+        if self.nick_of(id)? == nick {
+            return Ok(());
+        }
+        validate_chosen_nick(nick)?;
+        self.check_nick_banned(nick)?;
+        self.check_muted(id)?;
+        if self.accounts.is_registered(nick) && !self.is_logged_in_as(id, nick) {
+            return Err(ServerMessage::error(
+                ErrorCode::NickReserved,
+                format!("Nickname {} is registered, use /login to claim it", nick),
+            ));
+        }
+        self.rename(id, nick)
+    }
+
+    fn is_logged_in_as(&self, id: usize, account: &str) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|client| client.account.as_deref() == Some(account))
+    }
+
+    fn rename(&self, id: usize, nick: &str) -> Reply {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.iter().any(|(&other, c)| other != id && c.nick == nick) {
+            return Err(ServerMessage::error(ErrorCode::NickInUse, format!("Nickname {} is already in use", nick)));
+        }
+        let (old, rooms) = match clients.get_mut(&id) {
+            Some(client) if client.nick == nick => return Ok(()),
+            Some(client) => (std::mem::replace(&mut client.nick, nick.to_string()), client.rooms.clone()),
+            None => return Err(ServerMessage::error(ErrorCode::Internal, "Unknown client")),
+        };
+
+        // Everyone sharing a room with the client sees the change, and so does the client itself.
+        let message = ServerMessage::Nick { old: old.clone(), new: nick.to_string() };
+        for (&other, client) in clients.iter() {
+            if other == id || !client.rooms.is_disjoint(&rooms) {
+                if let Err(e) = client.send(&message) {
+                    eprintln!("Error sending to client #{}: {}", other, e);
+                }
+            }
+        }
+        drop(clients);
+        self.federation.publish(Event::Nick { old, new: nick.to_string() });
+        Ok(())
+    }
+
+    fn register(&self, id: usize, nick: &str, password: &str) -> Reply {
+        validate_chosen_nick(nick)?;
+        self.check_nick_banned(nick)?;
+        if self.is_authenticated(id) {
+            return Err(ServerMessage::error(ErrorCode::InvalidMessage, "You are already logged in"));
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(ServerMessage::error(
+                ErrorCode::InvalidCredentials,
+                format!("Password must be at least {} characters", MIN_PASSWORD_LEN),
+            ));
+        }
+        if self.clients.lock().unwrap().iter().any(|(&other, c)| other != id && c.nick == nick) {
+            return Err(ServerMessage::error(ErrorCode::NickInUse, format!("Nickname {} is already in use", nick)));
+        }
+
+        match self.accounts.register(nick, password) {
+            Ok(()) => println!("Client #{} registered account {}", id, nick),
+            Err(AccountError::Exists) => {
+                return Err(ServerMessage::error(ErrorCode::NickReserved, format!("Nickname {} is already registered", nick)));
+            }
+            Err(e) => {
+                eprintln!("Error registering account {}: {}", nick, e);
+                return Err(ServerMessage::error(ErrorCode::Internal, "Registration failed"));
+            }
+        }
+        self.sign_in(id, nick)
+    }
+
+    fn login(&self, id: usize, nick: &str, password: &str) -> Reply {
+        if self.is_authenticated(id) {
+            return Err(ServerMessage::error(ErrorCode::InvalidMessage, "You are already logged in"));
+        }
+        if !self.accounts.verify(nick, password) {
+            return Err(ServerMessage::error(ErrorCode::InvalidCredentials, "Invalid nickname or password"));
+        }
+        self.check_nick_banned(nick)?;
+        let logged_in_elsewhere = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .any(|client| client.account.as_deref() == Some(nick));
+        if logged_in_elsewhere {
+            return Err(ServerMessage::error(ErrorCode::NickInUse, format!("{} is already logged in", nick)));
+        }
+        println!("Client #{} logged in as {}", id, nick);
+        self.sign_in(id, nick)
+    }
+
+    fn sign_in(&self, id: usize, account: &str) -> Reply {
+        self.rename(id, account)?;
+        let joined_any = match self.clients.lock().unwrap().get_mut(&id) {
+            Some(client) => {
+                client.account = Some(account.to_string());
+                if let Some(key) = &client.key {
+                    self.account_keys.lock().unwrap().insert(account.to_string(), key.clone());
+                }
+                !client.rooms.is_empty()
+            }
+            None => return Err(ServerMessage::error(ErrorCode::Internal, "Unknown client")),
+        };
+        if !joined_any {
+            self.join(id, DEFAULT_ROOM)?;
+        }
+        self.deliver_offline_messages(id, account);
+        Ok(())
+    }
+
+    fn deliver_offline_messages(&self, id: usize, account: &str) {
+        let messages = match self.mailbox.take(account) {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("Error loading offline messages for {}: {}", account, e);
+                self.send_to(id, &ServerMessage::system("Could not load messages sent while you were offline"));
+                return;
+            }
+        };
+        if messages.is_empty() {
+            return;
+        }
+        self.send_to(id, &ServerMessage::system(format!("{} message(s) arrived while you were offline:", messages.len())));
+        for letter in messages {
+            self.send_to(id, &ServerMessage::Dm {
+                from: letter.entry.from,
+                to: account.to_string(),
+                text: letter.entry.text,
+                timestamp: letter.entry.timestamp,
+                encrypted: letter.encrypted,
+            });
+        }
+    }
+
+    fn dm(&self, id: usize, to: &str, text: &str, encrypted: bool) -> Reply {
+        validate_text(text)?;
+        let from = self.nick_of(id)?;
+        self.check_muted(id)?;
+        let entry = history::entry(&from, text);
+        let message = ServerMessage::Dm {
+            from,
+            to: to.to_string(),
+            text: text.to_string(),
+            timestamp: entry.timestamp,
+            encrypted,
+        };
+
+        let clients = self.clients.lock().unwrap();
+        let target = match clients.iter().find(|(_, client)| client.nick == to) {
+            Some((&target, _)) => target,
+            // Registered users get the message the next time they log in. The
+            // client map stays locked so they can't sign in halfway through.
+            None if self.accounts.is_registered(to) => {
+                if let Err(e) = self.mailbox.store(to, Letter { entry, encrypted }) {
+                    eprintln!("Error storing offline message for {}: {}", to, e);
+                    return Err(ServerMessage::error(ErrorCode::Internal, "Could not store the message"));
+                }
+                if let Some(client) = clients.get(&id) {
+                    let _ = client.send(&message);
+                    let _ = client.send(&ServerMessage::system(format!(
+                        "{} is offline and will get your message when they log in",
+                        to
+                    )));
+                }
+                return Ok(());
+            }
+            None => return Err(ServerMessage::error(ErrorCode::NoSuchUser, format!("No such user: {}", to))),
+        };
+        let recipients = if target == id { vec![id] } else { vec![target, id] };
+        for recipient in recipients {
+            if let Some(client) = clients.get(&recipient) {
+                if let Err(e) = client.send(&message) {
+                    eprintln!("Error sending to client #{}: {}", recipient, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn publish_key(&self, id: usize, key: String) -> Reply {
+        if BASE64.decode(&key).map_or(true, |bytes| bytes.len() != 32) {
+            return Err(ServerMessage::error(ErrorCode::InvalidMessage, "Keys must be 32 bytes of base64"));
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients
+            .get_mut(&id)
+            .ok_or_else(|| ServerMessage::error(ErrorCode::Internal, "Unknown client"))?;
+        if let Some(account) = &client.account {
+            self.account_keys.lock().unwrap().insert(account.clone(), key.clone());
+        }
+        client.key = Some(key);
+        Ok(())
+    }
+
+    fn get_key(&self, id: usize, nick: &str) -> Reply {
+        let key = {
+            let clients = self.clients.lock().unwrap();
+            match clients.values().find(|client| client.nick == nick) {
+                Some(client) => client.key.clone(),
+                None => self.account_keys.lock().unwrap().get(nick).cloned(),
+            }
+        };
+        self.send_to(id, &ServerMessage::Key { nick: nick.to_string(), key });
+        Ok(())
+    }
+
+    fn require_operator(&self, id: usize) -> Result<String, ServerMessage> {
+        let clients = self.clients.lock().unwrap();
+        match clients.get(&id) {
+            Some(Client { account: Some(account), nick, .. }) if self.moderation.is_operator(account) => Ok(nick.clone()),
+            _ => Err(ServerMessage::error(ErrorCode::PermissionDenied, "Only operators can do that")),
+        }
+    }
+
+    pub fn room_members(&self, room: &str) -> Vec<String> {
+        self.members(&self.clients.lock().unwrap(), room)
+    }
+
+    fn members(&self, clients: &HashMap<usize, Client>, room: &str) -> Vec<String> {
+        let mut nicks: Vec<String> = clients
+            .values()
+            .filter(|client| client.rooms.contains(room))
+            .map(|client| client.nick.clone())
+            .collect();
+        nicks.extend(self.federation.remote_members(room));
+        nicks.sort();
+        nicks
+    }
+
+    fn find_client(&self, nick: &str) -> Option<usize> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, client)| client.nick == nick)
+            .map(|(&id, _)| id)
+    }
+
+    // Tells everyone sharing a room with `target` (but not `target` itself) about a moderation action.
+    fn announce(&self, target: usize, text: &str) {
+        let clients = self.clients.lock().unwrap();
+        let rooms = match clients.get(&target) {
+            Some(client) => &client.rooms,
+            None => return,
+        };
+        let message = ServerMessage::system(text);
+        for (&id, client) in clients.iter() {
+            if id != target && !client.rooms.is_disjoint(rooms) {
+                let _ = client.send(&message);
+            }
+        }
+    }
+
+    fn remove_by_moderator(&self, target: usize, announcement: &str, notice: &str) {
+        self.announce(target, announcement);
+        self.disconnect(target, &ServerMessage::system(notice));
+    }
+
+    fn kick(&self, id: usize, nick: &str, reason: Option<&str>) -> Reply {
+        let by = self.require_operator(id)?;
+        let target = self
+            .find_client(nick)
+            .ok_or_else(|| ServerMessage::error(ErrorCode::NoSuchUser, format!("No such user: {}", nick)))?;
+        let reason = format_reason(reason)?;
+        println!("{} kicked {}{}", by, nick, reason);
+        self.remove_by_moderator(
+            target,
+            &format!("{} was kicked by {}{}", nick, by, reason),
+            &format!("You were kicked by {}{}", by, reason),
+        );
+        Ok(())
+    }
+
+    fn ban(&self, id: usize, target: &str, reason: Option<&str>) -> Reply {
+        let by = self.require_operator(id)?;
+        let reason = format_reason(reason)?;
+
+        let banned: Vec<(usize, String)> = if let Ok(ip) = target.parse::<IpAddr>() {
+            self.moderation.ban_ip(ip, true).map_err(persist_error)?;
+            self.clients
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(&other, client)| other != id && client.addr.ip() == ip)
+                .map(|(&id, client)| (id, client.nick.clone()))
+                .collect()
+        } else {
+            validate_name(target)?;
+            self.moderation.ban_nick(target, true).map_err(persist_error)?;
+            self.clients
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, client)| client.nick == target || client.account.as_deref() == Some(target))
+                .map(|(&id, client)| (id, client.nick.clone()))
+                .collect()
+        };
+
+        println!("{} banned {}{}", by, target, reason);
+        for (client, nick) in banned {
+            self.remove_by_moderator(
+                client,
+                &format!("{} was banned by {}{}", nick, by, reason),
+                &format!("You were banned by {}{}", by, reason),
+            );
+        }
+        Ok(())
+    }
+
+    fn unban(&self, id: usize, target: &str) -> Reply {
+        let by = self.require_operator(id)?;
+        let removed = match target.parse::<IpAddr>() {
+            Ok(ip) => self.moderation.ban_ip(ip, false),
+            Err(_) => self.moderation.ban_nick(target, false),
+        }
+        .map_err(persist_error)?;
+        if !removed {
+            return Err(ServerMessage::error(ErrorCode::NoSuchUser, format!("{} is not banned", target)));
+        }
+        println!("{} unbanned {}", by, target);
+        Ok(())
+    }
+
+    fn mute(&self, id: usize, nick: &str, seconds: u64) -> Reply {
+        let by = self.require_operator(id)?;
+        if seconds == 0 {
+            return Err(ServerMessage::error(ErrorCode::InvalidMessage, "Mute duration must be positive"));
+        }
+        let target = self
+            .find_client(nick)
+            .ok_or_else(|| ServerMessage::error(ErrorCode::NoSuchUser, format!("No such user: {}", nick)))?;
+        for mute in self.mute_targets(target) {
+            self.moderation.mute(mute, Duration::from_secs(seconds));
+        }
+        self.announce(target, &format!("{} was muted by {} for {}s", nick, by, seconds));
+        self.send_to(target, &ServerMessage::system(format!("You were muted by {} for {}s", by, seconds)));
+        Ok(())
+    }
+
+    fn unmute(&self, id: usize, nick: &str) -> Reply {
+        let by = self.require_operator(id)?;
+        // An account stays muted while it's offline, so it can be unmuted by name.
+        let target = self.find_client(nick);
+        let mutes = match target {
+            Some(target) => self.mute_targets(target),
+            None => vec![MuteTarget::Account(nick.to_string())],
+        };
+        let unmuted = mutes.iter().fold(false, |unmuted, mute| self.moderation.unmute(mute) | unmuted);
+        if !unmuted {
+            return Err(ServerMessage::error(ErrorCode::NoSuchUser, format!("{} is not muted", nick)));
+        }
+        if let Some(target) = target {
+            self.announce(target, &format!("{} was unmuted by {}", nick, by));
+            self.send_to(target, &ServerMessage::system(format!("You were unmuted by {}", by)));
+        }
+        Ok(())
+    }
+
+    // Operator status belongs to a registered account rather than a connection.
+    fn set_operator(&self, id: usize, account: &str, operator: bool) -> Reply {
+        let by = self.require_operator(id)?;
+        if !self.accounts.is_registered(account) {
+            return Err(ServerMessage::error(ErrorCode::NoSuchUser, format!("{} is not a registered account", account)));
+        }
+        let changed = self.moderation.set_operator(account, operator).map_err(persist_error)?;
+        if !changed {
+            return Ok(());
+        }
+
+        let text = if operator {
+            format!("{} is now an operator (by {})", account, by)
+        } else {
+            format!("{} is no longer an operator (by {})", account, by)
+        };
+        println!("{}", text);
+        if let Some(target) = self.find_client(account) {
+            self.announce(target, &text);
+            self.send_to(target, &ServerMessage::system(text));
+        }
+        Ok(())
+    }
+
+    fn history(&self, id: usize, room: &str, limit: usize) -> Reply {
+        self.require_room(id, room)?;
+        self.replay_history(id, room, limit.min(MAX_HISTORY_REQUEST))
+    }
+
+    fn upload(&self, id: usize, room: &str, name: &str, size: u64) -> Reply {
+        self.require_room(id, room)?;
+        self.check_muted(id)?;
+        let name = files::clean_name(name)
+            .ok_or_else(|| ServerMessage::error(ErrorCode::InvalidMessage, format!("Invalid file name '{}'", name)))?;
+        let upload = self.files.begin(id, room, &name, size).map_err(file_error)?;
+        // Replacing an unfinished upload drops it, which deletes its temporary file.
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.upload = Some(upload);
+        }
+        Ok(())
+    }
+
+    fn take_upload(&self, id: usize) -> Result<Upload, ServerMessage> {
+        self.clients
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .and_then(|client| client.upload.take())
+            .ok_or_else(|| ServerMessage::error(ErrorCode::TransferFailed, "No upload in progress"))
+    }
+
+    fn upload_chunk(&self, id: usize, data: &str) -> Reply {
+        let data = BASE64
+            .decode(data)
+            .map_err(|_| ServerMessage::error(ErrorCode::InvalidMessage, "Upload chunks must be base64"))?;
+        // The upload is taken out of the client map so the disk write doesn't
+        // hold up everyone else.
+        let mut upload = self.take_upload(id)?;
+        upload.write(&data).map_err(file_error)?;
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.upload = Some(upload);
+        }
+        Ok(())
+    }
+
+    fn upload_end(&self, id: usize, sha256: &str) -> Reply {
+        let upload = self.take_upload(id)?;
+        let nick = self.nick_of(id)?;
+        let stored = self.files.finish(upload, sha256, &nick).map_err(file_error)?;
+        println!("{} shared {} ({} bytes) in #{} as {}", nick, stored.name, stored.size, stored.room, stored.id);
+        self.broadcast(&stored.room, &ServerMessage::File {
+            room: stored.room.clone(),
+            from: stored.from.clone(),
+            file: Box::new(stored.shared()),
+            timestamp: stored.timestamp,
+        });
+        Ok(())
+    }
+
+    fn download(&self, id: usize, file: &str) -> Reply {
+        let no_such_file = || ServerMessage::error(ErrorCode::NoSuchFile, format!("No such file: {}", file));
+        let (stored, path) = match self.files.get(file) {
+            Ok(Some(found)) => found,
+            Ok(None) => return Err(no_such_file()),
+            Err(e) => {
+                eprintln!("Error looking up file {}: {}", file, e);
+                return Err(no_such_file());
+            }
+        };
+        // Only members of the room a file was shared in may fetch it.
+        self.require_room(id, &stored.room).map_err(|_| no_such_file())?;
+        let download = Download::open(stored, &path).map_err(|e| {
+            eprintln!("Error reading file {}: {}", path.display(), e);
+            ServerMessage::error(ErrorCode::Internal, "Could not read the file")
+        })?;
+
+        if let Some(client) = self.clients.lock().unwrap().get(&id) {
+            let _ = client.outbox.send(Outgoing::Download(Box::new(download)));
+        }
+        Ok(())
+    }
+
+    fn replay_history(&self, id: usize, room: &str, limit: usize) -> Reply {
+        let messages = self.history.recent(room, limit).map_err(|e| {
+            eprintln!("Error reading history for #{}: {}", room, e);
+            ServerMessage::error(ErrorCode::Internal, "History is unavailable")
+        })?;
+        self.send_to(id, &ServerMessage::History { room: room.to_string(), messages });
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use crate::server::{Outgoing, Server, Traffic};
+use chat_protocol::encode;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+// Skips the rest of an over-long line. Returns false on EOF.
+fn discard_line(reader: &mut impl BufRead) -> io::Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(true);
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+pub fn handle_client(stream: TcpStream, id: usize, server: Arc<Server>) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream.try_clone().unwrap();
+    // A peer that accepts no data for a whole heartbeat timeout is treated as gone.
+    let _ = writer.set_write_timeout(Some(server.heartbeat_config().timeout));
+
+    let traffic = Arc::new(Traffic::default());
+    let (outbox, messages) = mpsc::channel::<Outgoing>();
+    let writer_traffic = Arc::clone(&traffic);
+    let writer_thread = thread::spawn(move || {
+        'writer: for outgoing in messages {
+            let Some(messages) = outgoing.into_messages() else { break };
+            for message in messages {
+                let line = encode(&message);
+                if writer.write_all(line.as_bytes()).is_err() {
+                    break 'writer;
+                }
+                writer_traffic.add_sent(line.len());
+            }
+        }
+        // Also wakes up the reader when the server dropped the client on its own,
+        // e.g. after a ping timeout.
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    server.add_client(id, addr, outbox, Arc::clone(&traffic));
+
+    let max_line_len = server.max_line_len();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // Reading one byte past the limit tells an over-long line apart from one that just fits.
+        match reader.by_ref().take(max_line_len as u64 + 1).read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(n) => {
+                traffic.add_received(n);
+                if line.len() > max_line_len && !line.ends_with(b"\n") {
+                    server.line_too_long(id);
+                    match discard_line(&mut reader) {
+                        Ok(true) => continue,
+                        _ => break,
+                    }
+                }
+                if !server.handle_line(id, &String::from_utf8_lossy(&line)) {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading from client #{}: {}", id, e);
+                break;
+            }
+        }
+    }
+
+    // Dropping the client's outbox lets the writer flush what is queued and exit.
+    server.remove_client(id);
+    let _ = writer_thread.join();
+    let _ = stream.shutdown(Shutdown::Both);
+}
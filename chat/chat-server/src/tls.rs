@@ -0,0 +1,155 @@
+use crate::server::{Outgoing, Server, Traffic};
+use chat_protocol::encode;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Like WebSocket connections, a TLS stream can't be read and written from two
+// threads, so a single thread polls the socket and the client's outbox.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Loads the certificate chain and key, generating a self-signed certificate
+// for localhost first if neither file exists yet.
+pub fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    if !cert_path.exists() && !key_path.exists() {
+        generate_self_signed(cert_path, key_path)?;
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("no private key in {}", key_path.display())))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path) -> io::Result<()> {
+    let names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    fs::write(cert_path, certified.cert.pem())?;
+    // Only the user running the server may read the private key.
+    let mut key = OpenOptions::new().write(true).create_new(true).mode(0o600).open(key_path)?;
+    key.write_all(certified.key_pair.serialize_pem().as_bytes())?;
+    println!(
+        "Generated self-signed certificate {} (key {})",
+        cert_path.display(),
+        key_path.display()
+    );
+    Ok(())
+}
+
+pub fn handle_tls_client(stream: TcpStream, id: usize, server: Arc<Server>, config: Arc<ServerConfig>) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let write_timeout = server.heartbeat_config().timeout;
+    let mut tls = match accept(stream, config, write_timeout) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("TLS handshake with client #{} failed: {}", id, e);
+            return;
+        }
+    };
+
+    let traffic = Arc::new(Traffic::default());
+    let (outbox, messages) = mpsc::channel();
+    server.add_client(id, addr, outbox, Arc::clone(&traffic));
+
+    let max_line_len = server.max_line_len();
+    let mut pending = Vec::new();
+    let mut discarding = false;
+    let mut buffer = [0u8; 4096];
+
+    'session: loop {
+        loop {
+            match messages.try_recv() {
+                Ok(outgoing) => {
+                    let Some(messages) = outgoing.into_messages() else { break 'session };
+                    for message in messages {
+                        let line = encode(&message);
+                        if tls.write_all(line.as_bytes()).is_err() {
+                            break 'session;
+                        }
+                        traffic.add_sent(line.len());
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'session,
+            }
+        }
+
+        match tls.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                traffic.add_received(n);
+                pending.extend_from_slice(&buffer[..n]);
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+                    if std::mem::take(&mut discarding) {
+                        continue;
+                    }
+                    if line.len() > max_line_len + 1 {
+                        server.line_too_long(id);
+                        continue;
+                    }
+                    if !server.handle_line(id, &String::from_utf8_lossy(&line)) {
+                        break 'session;
+                    }
+                }
+                // Drop the start of an over-long line and skip ahead to its end.
+                if pending.len() > max_line_len {
+                    if !discarding {
+                        server.line_too_long(id);
+                        discarding = true;
+                    }
+                    pending.clear();
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            // Clients that just drop the connection without a close_notify.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                eprintln!("Error reading from TLS client #{}: {}", id, e);
+                break;
+            }
+        }
+    }
+
+    server.remove_client(id);
+    while let Ok(Outgoing::Message(message)) = messages.try_recv() {
+        if tls.write_all(encode(&message).as_bytes()).is_err() {
+            return;
+        }
+    }
+    tls.conn.send_close_notify();
+    let _ = tls.flush();
+}
+
+fn accept(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    write_timeout: Duration,
+) -> io::Result<StreamOwned<ServerConnection, TcpStream>> {
+    let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+    let mut tls = StreamOwned::new(conn, stream);
+
+    tls.sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+    tls.sock.set_read_timeout(Some(POLL_INTERVAL))?;
+    tls.sock.set_write_timeout(Some(write_timeout))?;
+    Ok(tls)
+}
@@ -0,0 +1,129 @@
+use crate::server::{Outgoing, Server, Traffic};
+use chat_protocol::encode;
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error as WsError, Message};
+
+// A WebSocket can't be split into independent read and write halves, so each
+// connection is served by a single thread that alternates between reading
+// with a short timeout and flushing the client's outbox.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn listen(addr: &str, server: Arc<Server>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("WebSocket gateway listening on {}", addr);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let id = server.next_id();
+                println!("New WebSocket client connected #{}", id);
+
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    handle_websocket_client(stream, id, server);
+                });
+            }
+            Err(e) => {
+                eprintln!("Error accepting WebSocket client: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn text_frame(line: String) -> Message {
+    Message::Text(line.trim_end().to_string())
+}
+
+fn handle_websocket_client(stream: TcpStream, id: usize, server: Arc<Server>) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    // Messages over max_line_len are dropped with a penalty; far larger ones
+    // are refused by tungstenite before they are buffered.
+    let max_line_len = server.max_line_len();
+    let config = WebSocketConfig {
+        max_message_size: Some(max_line_len * 4),
+        max_frame_size: Some(max_line_len * 4),
+        ..Default::default()
+    };
+
+    let mut socket = match tungstenite::accept_with_config(stream, Some(config)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("WebSocket handshake with client #{} failed: {}", id, e);
+            return;
+        }
+    };
+    // A peer that accepts no data for a whole heartbeat timeout is treated as gone.
+    let configured = socket
+        .get_ref()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .and_then(|_| socket.get_ref().set_write_timeout(Some(server.heartbeat_config().timeout)));
+    if let Err(e) = configured {
+        eprintln!("Error configuring WebSocket client #{}: {}", id, e);
+        return;
+    }
+
+    // Counts message payloads, not WebSocket framing.
+    let traffic = Arc::new(Traffic::default());
+    let (outbox, messages) = mpsc::channel();
+    server.add_client(id, addr, outbox, Arc::clone(&traffic));
+
+    'session: loop {
+        loop {
+            match messages.try_recv() {
+                Ok(outgoing) => {
+                    let Some(messages) = outgoing.into_messages() else { break 'session };
+                    for message in messages {
+                        let frame = text_frame(encode(&message));
+                        traffic.add_sent(frame.len());
+                        if socket.send(frame).is_err() {
+                            break 'session;
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'session,
+            }
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                traffic.add_received(text.len());
+                if text.len() > max_line_len {
+                    server.line_too_long(id);
+                    continue;
+                }
+                if !server.handle_line(id, &text) {
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) => break,
+            Ok(_) => {}
+            Err(WsError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => {
+                eprintln!("Error reading from WebSocket client #{}: {}", id, e);
+                break;
+            }
+        }
+    }
+
+    server.remove_client(id);
+    // Deliver anything queued before the client was removed, e.g. the ack for `quit`.
+    while let Ok(Outgoing::Message(message)) = messages.try_recv() {
+        if socket.send(text_frame(encode(&message))).is_err() {
+            return;
+        }
+    }
+    let _ = socket.close(None);
+    let _ = socket.flush();
+}
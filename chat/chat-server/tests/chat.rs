@@ -0,0 +1,244 @@
+mod common;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chat_protocol::{ClientMessage, ErrorCode, HistoryEntry, ServerMessage};
+use common::{TestClient, TestServer};
+use sha2::{Digest, Sha256};
+use std::thread;
+
+fn chat(room: &str, text: &str) -> ClientMessage {
+    ClientMessage::Chat { room: room.to_string(), text: text.to_string() }
+}
+
+fn texts(messages: &[ServerMessage]) -> Vec<(String, String)> {
+    messages
+        .iter()
+        .filter_map(|message| match message {
+            ServerMessage::Chat { from, text, .. } => Some((from.clone(), text.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn join_replies_with_members_history_and_ack() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &[]);
+    let mut bob = server.connect_as("bob", &["rust"]);
+
+    let reply = alice.request(ClientMessage::Join { room: "rust".to_string() }).expect_ack("join");
+    assert_eq!(
+        reply,
+        vec![
+            ServerMessage::Members { room: "rust".to_string(), nicks: vec!["alice".to_string(), "bob".to_string()] },
+            ServerMessage::Join { room: "rust".to_string(), nick: "alice".to_string() },
+            ServerMessage::History { room: "rust".to_string(), messages: Vec::new() },
+            ServerMessage::Ack { command: "join".to_string() },
+        ]
+    );
+    assert_eq!(bob.drain(), vec![ServerMessage::Join { room: "rust".to_string(), nick: "alice".to_string() }]);
+}
+
+#[test]
+fn commands_are_answered_with_errors_when_they_fail() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &["rust"]);
+    let _bob = server.connect_as("bob", &[]);
+
+    alice.request(chat("go", "hello")).expect_error(ErrorCode::NotInRoom);
+    alice.request(ClientMessage::Nick { nick: "bob".to_string() }).expect_error(ErrorCode::NickInUse);
+    alice.request(ClientMessage::Nick { nick: "not valid".to_string() }).expect_error(ErrorCode::InvalidName);
+    alice.request(ClientMessage::Join { room: "../etc".to_string() }).expect_error(ErrorCode::InvalidName);
+    alice
+        .request(ClientMessage::Dm { to: "nobody".to_string(), text: "hi".to_string(), encrypted: false })
+        .expect_error(ErrorCode::NoSuchUser);
+    alice.request(ClientMessage::Download { id: "0123456789abcdef".to_string() }).expect_error(ErrorCode::NoSuchFile);
+
+    alice.send_raw("this is not json\n");
+    assert!(matches!(alice.recv(), ServerMessage::Error { code: ErrorCode::InvalidMessage, .. }));
+    alice.send_raw("{\"v\":99,\"type\":\"quit\"}\n");
+    assert!(matches!(alice.recv(), ServerMessage::Error { code: ErrorCode::UnsupportedVersion, .. }));
+
+    // Still usable after all that.
+    alice.request(chat("rust", "still here")).expect_ack("chat");
+}
+
+#[test]
+fn dms_reach_the_recipient_and_echo_to_the_sender() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &[]);
+    let mut bob = server.connect_as("bob", &[]);
+    let mut carol = server.connect_as("carol", &[]);
+
+    let dm = ClientMessage::Dm { to: "bob".to_string(), text: "psst".to_string(), encrypted: false };
+    let reply = alice.request(dm).expect_ack("dm");
+    assert!(matches!(&reply[..], [ServerMessage::Dm { from, to, text, .. }, _] if from == "alice" && to == "bob" && text == "psst"));
+    assert!(matches!(&bob.drain()[..], [ServerMessage::Dm { from, text, .. }] if from == "alice" && text == "psst"));
+    assert_eq!(carol.drain(), Vec::new());
+}
+
+// Shares `contents` in `room` and returns the id it got.
+fn share(client: &mut TestClient, room: &str, contents: &[u8]) -> String {
+    let upload = ClientMessage::Upload { room: room.to_string(), name: "notes.txt".to_string(), size: contents.len() as u64 };
+    client.request(upload).expect_ack("upload");
+    for chunk in contents.chunks(2048) {
+        client.request(ClientMessage::UploadChunk { data: BASE64.encode(chunk) }).expect_ack("upload_chunk");
+    }
+    let sha256 = hex::encode(Sha256::digest(contents));
+    let reply = client.request(ClientMessage::UploadEnd { sha256 }).expect_ack("upload_end");
+    match &reply[..] {
+        [ServerMessage::File { file, .. }, _] => file.id.clone(),
+        other => panic!("expected the shared file, got {:?}", other),
+    }
+}
+
+#[test]
+fn shared_files_can_only_be_downloaded_from_their_room() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &["rust", "go"]);
+    let mut bob = server.connect_as("bob", &["rust"]);
+    let mut carol = server.connect_as("carol", &["go"]);
+
+    let contents = vec![7u8; 100_000];
+    let in_rust = share(&mut alice, "rust", &contents);
+    let in_go = share(&mut alice, "go", &contents);
+    assert_ne!(in_rust, in_go);
+
+    carol.drain();
+    carol.request(ClientMessage::Download { id: in_rust.clone() }).expect_error(ErrorCode::NoSuchFile);
+    let reply = carol.request(ClientMessage::Download { id: in_go }).expect_ack("download");
+    assert!(matches!(&reply[0], ServerMessage::FileStart { file } if file.size == contents.len() as u64));
+
+    // Sharing the same contents in #go didn't move the #rust share.
+    bob.drain();
+    let reply = bob.request(ClientMessage::Download { id: in_rust.clone() }).expect_ack("download");
+    let mut received = Vec::new();
+    for message in &reply {
+        if let ServerMessage::FileChunk { id, data } = message {
+            assert_eq!(id, &in_rust);
+            received.extend(BASE64.decode(data).unwrap());
+        }
+    }
+    assert_eq!(received, contents);
+    assert!(matches!(&reply[reply.len() - 2], ServerMessage::FileEnd { id } if *id == in_rust));
+}
+
+#[test]
+fn chat_is_broadcast_to_everyone_in_the_room_in_one_order() {
+    const PER_SENDER: usize = 50;
+    let server = TestServer::start();
+    let mut clients: Vec<TestClient> =
+        ["alice", "bob", "carol"].iter().map(|nick| server.connect_as(nick, &["rust"])).collect();
+    let mut outsider = server.connect_as("dave", &["go"]);
+    for client in &mut clients {
+        client.drain();
+    }
+
+    // Everyone talks at once, each from its own thread.
+    let senders: Vec<_> = clients
+        .into_iter()
+        .map(|mut client| {
+            thread::spawn(move || {
+                for i in 0..PER_SENDER {
+                    client.send(&chat("rust", &format!("{} {}", client.nick, i)));
+                }
+                let mut acks = 0;
+                let mut messages = client.recv_until(|message| {
+                    acks += usize::from(matches!(message, ServerMessage::Ack { .. }));
+                    acks == PER_SENDER
+                });
+                // Others may still be talking after our last ack.
+                messages.extend(client.drain());
+                (client.nick.clone(), texts(&messages))
+            })
+        })
+        .collect();
+    let received: Vec<(String, Vec<(String, String)>)> = senders.into_iter().map(|t| t.join().unwrap()).collect();
+
+    for (nick, chats) in &received {
+        assert_eq!(chats.len(), 3 * PER_SENDER, "{} missed messages", nick);
+        // Each sender's messages arrive in the order they were sent.
+        for sender in ["alice", "bob", "carol"] {
+            let from_sender: Vec<&String> = chats.iter().filter(|(from, _)| from == sender).map(|(_, text)| text).collect();
+            let expected: Vec<String> = (0..PER_SENDER).map(|i| format!("{} {}", sender, i)).collect();
+            assert_eq!(from_sender, expected.iter().collect::<Vec<_>>(), "{} saw {}'s messages out of order", nick, sender);
+        }
+    }
+    // And everyone sees the same interleaving.
+    assert_eq!(received[0].1, received[1].1);
+    assert_eq!(received[1].1, received[2].1);
+    assert_eq!(texts(&outsider.drain()), Vec::new());
+}
+
+#[test]
+fn history_is_replayed_to_late_joiners() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &["rust"]);
+    for text in ["one", "two", "three"] {
+        alice.request(chat("rust", text)).expect_ack("chat");
+    }
+
+    let mut bob = server.connect_as("bob", &[]);
+    let reply = bob.request(ClientMessage::Join { room: "rust".to_string() }).expect_ack("join");
+    let history = reply.iter().find_map(|message| match message {
+        ServerMessage::History { messages, .. } => Some(messages.clone()),
+        _ => None,
+    });
+    let history: Vec<(String, String)> = history
+        .unwrap()
+        .into_iter()
+        .map(|HistoryEntry { from, text, .. }| (from, text))
+        .collect();
+    assert_eq!(
+        history,
+        ["one", "two", "three"].map(|text| ("alice".to_string(), text.to_string())).to_vec()
+    );
+}
+
+#[test]
+fn disconnected_clients_are_removed_and_their_rooms_told() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &["rust", "go"]);
+    let carol = server.connect_as("carol", &["rust", "go"]);
+    server.wait_for_clients(2);
+    alice.drain();
+
+    let carol_id = carol.id;
+    drop(carol);
+    server.wait_for_clients(1);
+    assert!(server.server.clients().iter().all(|client| client.id != carol_id));
+
+    let mut leaves: Vec<ServerMessage> = alice.drain();
+    leaves.sort_by_key(|message| format!("{:?}", message));
+    assert_eq!(
+        leaves,
+        vec![
+            ServerMessage::Leave { room: "go".to_string(), nick: "carol".to_string() },
+            ServerMessage::Leave { room: "rust".to_string(), nick: "carol".to_string() },
+        ]
+    );
+
+    // The nick is free again and the room no longer lists carol.
+    let mut dave = server.connect();
+    dave.request(ClientMessage::Nick { nick: "carol".to_string() }).expect_ack("nick");
+    let reply = dave.request(ClientMessage::Join { room: "rust".to_string() }).expect_ack("join");
+    assert_eq!(
+        reply[0],
+        ServerMessage::Members { room: "rust".to_string(), nicks: vec!["alice".to_string(), "carol".to_string()] }
+    );
+}
+
+#[test]
+fn quit_is_acknowledged_before_the_connection_closes() {
+    let server = TestServer::start();
+    let mut alice = server.connect_as("alice", &["rust"]);
+    let mut bob = server.connect_as("bob", &["rust"]);
+    server.wait_for_clients(2);
+    alice.drain();
+
+    assert_eq!(bob.request(ClientMessage::Quit).expect_ack("quit").len(), 1);
+    bob.expect_closed();
+    server.wait_for_clients(1);
+    assert_eq!(alice.drain(), vec![ServerMessage::Leave { room: "rust".to_string(), nick: "bob".to_string() }]);
+}
@@ -0,0 +1,195 @@
+// Runs a server in-process on an ephemeral port and drives it with scripted
+// clients speaking the JSON line protocol.
+
+use chat_protocol::{decode, encode, ClientMessage, ErrorCode, ServerMessage};
+use chat_server::accounts::Accounts;
+use chat_server::files::FileStore;
+use chat_server::flood::FloodConfig;
+use chat_server::history::History;
+use chat_server::mailbox::Mailbox;
+use chat_server::moderation::Moderation;
+use chat_server::server::{Config, Server};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// How long a client waits for a message it expects.
+const TIMEOUT: Duration = Duration::from_secs(5);
+// How long a client has to stay silent to count as drained.
+const QUIET: Duration = Duration::from_millis(100);
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub server: Arc<Server>,
+    _dir: TempDir,
+}
+
+impl TestServer {
+    // A server without flood limits, so tests can send as fast as they like.
+    pub fn start() -> Self {
+        TestServer::with_config(Config {
+            flood: FloodConfig { rate: 10_000.0, burst: 10_000.0, ..FloodConfig::default() },
+            ..Config::default()
+        })
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Arc::new(Server::new(
+            config,
+            History::open(dir.path().join("history")).unwrap(),
+            Accounts::open(dir.path().join("accounts.json")).unwrap(),
+            Moderation::open(dir.path().join("moderation.json")).unwrap(),
+            Mailbox::open(dir.path().join("mailbox.json")).unwrap(),
+            FileStore::open(dir.path().join("files"), 1024 * 1024).unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = Arc::clone(&server);
+        thread::spawn(move || chat_server::serve(listener, accepting, None));
+        TestServer { addr, server, _dir: dir }
+    }
+
+    // Connects and reads the greeting, up to the end of the automatic join of #lobby.
+    pub fn connect(&self) -> TestClient {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut client = TestClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+            id: 0,
+            nick: String::new(),
+        };
+        match client.recv() {
+            ServerMessage::Welcome { id, nick } => {
+                client.id = id;
+                client.nick = nick;
+            }
+            other => panic!("expected a welcome, got {:?}", other),
+        }
+        client.recv_until(|message| matches!(message, ServerMessage::History { .. }));
+        client
+    }
+
+    // A client with the given nick that is in `rooms` and nowhere else, with
+    // nothing left to read.
+    pub fn connect_as(&self, nick: &str, rooms: &[&str]) -> TestClient {
+        let mut client = self.connect();
+        client.request(ClientMessage::Nick { nick: nick.to_string() }).expect_ack("nick");
+        client.nick = nick.to_string();
+        for room in rooms {
+            client.request(ClientMessage::Join { room: room.to_string() }).expect_ack("join");
+        }
+        if !rooms.contains(&"lobby") {
+            client.request(ClientMessage::Leave { room: "lobby".to_string() }).expect_ack("leave");
+        }
+        client
+    }
+
+    // Waits for the server's client map to reach `count`.
+    pub fn wait_for_clients(&self, count: usize) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.server.client_count() != count {
+            assert!(
+                Instant::now() < deadline,
+                "expected {} clients, server has {}",
+                count,
+                self.server.client_count()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+pub struct TestClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    pub id: usize,
+    pub nick: String,
+}
+
+// Everything the server sent in response to one message, ending with its ack or error.
+pub struct Reply(pub Vec<ServerMessage>);
+
+impl Reply {
+    pub fn expect_ack(self, command: &str) -> Vec<ServerMessage> {
+        match self.0.last() {
+            Some(ServerMessage::Ack { command: acked }) if acked == command => self.0,
+            other => panic!("expected an ack for {}, got {:?}", command, other),
+        }
+    }
+
+    pub fn expect_error(self, code: ErrorCode) -> Vec<ServerMessage> {
+        match self.0.last() {
+            Some(ServerMessage::Error { code: got, .. }) if *got == code => self.0,
+            other => panic!("expected a {:?} error, got {:?}", code, other),
+        }
+    }
+}
+
+impl TestClient {
+    pub fn send(&mut self, message: &ClientMessage) {
+        self.send_raw(&encode(message));
+    }
+
+    pub fn send_raw(&mut self, line: &str) {
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    pub fn recv(&mut self) -> ServerMessage {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => panic!("{}: connection closed", self.nick),
+            Ok(_) => decode(&line).unwrap_or_else(|e| panic!("{}: unreadable message {:?}: {}", self.nick, line, e)),
+            Err(e) => panic!("{}: nothing received: {}", self.nick, e),
+        }
+    }
+
+    // Reads up to and including the first message matching `done`.
+    pub fn recv_until(&mut self, mut done: impl FnMut(&ServerMessage) -> bool) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        loop {
+            let message = self.recv();
+            let last = done(&message);
+            messages.push(message);
+            if last {
+                return messages;
+            }
+        }
+    }
+
+    pub fn request(&mut self, message: ClientMessage) -> Reply {
+        self.send(&message);
+        Reply(self.recv_until(|message| matches!(message, ServerMessage::Ack { .. } | ServerMessage::Error { .. })))
+    }
+
+    // Returns whatever arrives until the server has been quiet for a moment.
+    pub fn drain(&mut self) -> Vec<ServerMessage> {
+        self.stream.set_read_timeout(Some(QUIET)).unwrap();
+        let mut messages = Vec::new();
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => messages.push(decode(&line).unwrap()),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => panic!("{}: {}", self.nick, e),
+            }
+        }
+        self.stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        messages
+    }
+
+    pub fn expect_closed(&mut self) {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {}
+            Ok(_) => panic!("{}: expected the connection to close, got {}", self.nick, line.trim_end()),
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) => panic!("{}: expected the connection to close: {}", self.nick, e),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::{
+    handle_timeout, progress_bar, read_status, read_string_from_stream, read_u64_from_stream, receive_body,
+    send_body, write_ok, TransferError, KIND_DIR,
+};
+use indicatif::{HumanBytes, ProgressBar};
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt, Error as IoError, ErrorKind},
+    net::TcpStream,
+};
+
+const ENTRY_FILE: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+
+// A directory goes over the wire as a manifest listing every directory and
+// file in it, parents before their contents, followed by the contents of
+// each file in manifest order sent like a single file.
+struct Entry {
+    // Relative to the parent of the directory being sent, so the first entry
+    // is the directory itself. Components are separated by '/'.
+    path: String,
+    is_dir: bool,
+    size: u64,
+    mode: u32,
+    // Seconds since the epoch
+    mtime: u64,
+}
+
+// Lists `root` and everything under it. Symlinks and special files are
+// skipped, as are names that aren't UTF-8.
+async fn walk(root: &Path) -> Result<Vec<(Entry, PathBuf)>, TransferError> {
+    let root = fs::canonicalize(root).await?;
+    let name = match root.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => {
+            let message = format!("can't send {} as a directory", root.display());
+            return Err(IoError::new(ErrorKind::InvalidInput, message).into());
+        }
+    };
+
+    let mut entries = Vec::new();
+    let mut pending = vec![(root, name)];
+    while let Some((path, relative)) = pending.pop() {
+        let metadata = fs::symlink_metadata(&path).await?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let mut entry = Entry {
+            path: relative,
+            is_dir: metadata.is_dir(),
+            size: 0,
+            mode: metadata.permissions().mode() & 0o777,
+            mtime,
+        };
+
+        if metadata.is_dir() {
+            let mut children = Vec::new();
+            let mut listing = fs::read_dir(&path).await?;
+            while let Some(child) = listing.next_entry().await? {
+                children.push(child.file_name());
+            }
+            children.sort();
+            // Reversed, so they come off the stack in order.
+            for child in children.into_iter().rev() {
+                match child.to_str() {
+                    Some(name) => pending.push((path.join(name), format!("{}/{}", entry.path, name))),
+                    None => println!("Skipping {}: name is not UTF-8", path.join(&child).display()),
+                }
+            }
+        } else if metadata.is_file() {
+            entry.size = metadata.len();
+        } else {
+            println!("Skipping {}: not a regular file or directory", path.display());
+            continue;
+        }
+        entries.push((entry, path));
+    }
+    Ok(entries)
+}
+
+pub async fn send_dir(stream: &mut TcpStream, root: &Path, chunk_size: usize) -> Result<(), TransferError> {
+    let entries = walk(root).await?;
+    let files = entries.iter().filter(|(entry, _)| !entry.is_dir).count();
+    let total: u64 = entries.iter().map(|(entry, _)| entry.size).sum();
+    println!("Sending directory: {} ({} files, {})", entries[0].0.path, files, HumanBytes(total));
+
+    // Built up front so the manifest doesn't go out one field per packet.
+    let mut manifest = vec![KIND_DIR];
+    manifest.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+    for (entry, _) in &entries {
+        manifest.extend_from_slice(&(entry.path.len() as u16).to_be_bytes());
+        manifest.extend_from_slice(entry.path.as_bytes());
+        manifest.push(if entry.is_dir { ENTRY_DIR } else { ENTRY_FILE });
+        manifest.extend_from_slice(&entry.size.to_be_bytes());
+        manifest.extend_from_slice(&u64::from(entry.mode).to_be_bytes());
+        manifest.extend_from_slice(&entry.mtime.to_be_bytes());
+    }
+    handle_timeout(stream.write_all(&manifest)).await?;
+    handle_timeout(stream.flush()).await?;
+
+    let pb = progress_bar(total);
+    let mut resumed = 0;
+    for (entry, path) in entries.iter().filter(|(entry, _)| !entry.is_dir) {
+        let mut file = File::open(path).await?;
+        resumed += send_body(stream, &mut file, &entry.path, entry.size, chunk_size, &pb).await?.resumed_from;
+    }
+    // The receiver has everything once it has set the directories' metadata.
+    read_status(stream, &entries[0].0.path).await?;
+
+    pb.finish_with_message("Transfer completed");
+    if resumed > 0 {
+        println!("Resumed after {} bytes the receiver already had", resumed);
+    }
+    println!("Sent {} files in {} directories", files, entries.len() - files);
+
+    Ok(())
+}
+
+async fn read_entry(stream: &mut TcpStream) -> Result<Entry, TransferError> {
+    let path = read_string_from_stream(stream).await?;
+    let is_dir = match handle_timeout(stream.read_u8()).await? {
+        ENTRY_FILE => false,
+        ENTRY_DIR => true,
+        kind => return Err(TransferError::Protocol(format!("unknown manifest entry kind {}", kind))),
+    };
+    let size = read_u64_from_stream(stream).await?;
+    let mode = read_u64_from_stream(stream).await? as u32 & 0o777;
+    let mtime = read_u64_from_stream(stream).await?;
+    Ok(Entry { path, is_dir, size, mode, mtime })
+}
+
+// Where a manifest path goes under `output`. Only plain names are allowed,
+// and every parent must be a directory listed earlier in the manifest, which
+// we created ourselves, so nothing can be written outside `output` or
+// through a symlink already there.
+fn target(output: &Path, path: &str, dirs: &HashSet<String>) -> Result<PathBuf, TransferError> {
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(TransferError::Protocol(format!("unsafe path in manifest: {:?}", path)));
+    }
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        if !dirs.contains(parent) {
+            return Err(TransferError::Protocol(format!("{:?} comes before its directory", path)));
+        }
+    }
+    Ok(output.join(relative))
+}
+
+// Sets the modification time first, as the new permissions may not allow it.
+fn restore_metadata(path: &Path, entry: &Entry) -> Result<(), IoError> {
+    std::fs::File::open(path)?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode))
+}
+
+// A manifest as received, checked to start with the directory being sent
+// and to list nothing outside it.
+pub struct Manifest {
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    pub fn root(&self) -> &str {
+        &self.entries[0].path
+    }
+
+    pub fn files(&self) -> usize {
+        self.entries.iter().filter(|entry| !entry.is_dir).count()
+    }
+
+    pub fn dirs(&self) -> usize {
+        self.entries.len() - self.files()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+}
+
+pub struct TreeReceived {
+    pub resumed: u64,
+}
+
+// A single file or directory name, not a path.
+fn is_plain_name(name: &str) -> bool {
+    !name.contains('/') && matches!(Path::new(name).components().collect::<Vec<_>>()[..], [Component::Normal(_)])
+}
+
+pub async fn read_manifest(stream: &mut TcpStream) -> Result<Manifest, TransferError> {
+    let count = read_u64_from_stream(stream).await?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        entries.push(read_entry(stream).await?);
+    }
+    check_manifest(entries)
+}
+
+fn check_manifest(entries: Vec<Entry>) -> Result<Manifest, TransferError> {
+    let root = match entries.first() {
+        Some(entry) if entry.is_dir && is_plain_name(&entry.path) => entry.path.clone(),
+        _ => return Err(TransferError::Protocol("manifest doesn't start with a directory".to_string())),
+    };
+    // Everything else must be inside the root, so the tree is all that's
+    // written and claiming the root covers all of it.
+    let prefix = format!("{}/", root);
+    if let Some(entry) = entries[1..].iter().find(|entry| !entry.path.starts_with(&prefix)) {
+        return Err(TransferError::Protocol(format!("{:?} is outside {:?}", entry.path, root)));
+    }
+    Ok(Manifest { entries })
+}
+
+// Recreates the tree in `manifest` under `output` and receives every file in it.
+pub async fn receive_tree(
+    stream: &mut TcpStream,
+    output: &Path,
+    manifest: &Manifest,
+    chunk_size: usize,
+    pb: &ProgressBar,
+) -> Result<TreeReceived, TransferError> {
+    let entries = &manifest.entries;
+
+    // Directories are kept writable by us until they're filled, in case an
+    // earlier attempt already restored read-only permissions.
+    fs::create_dir_all(output).await.map_err(TransferError::Disk)?;
+    let mut dirs = HashSet::new();
+    let mut targets = Vec::new();
+    for entry in entries {
+        let path = target(output, &entry.path, &dirs)?;
+        if entry.is_dir {
+            match fs::create_dir(&path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && fs::symlink_metadata(&path).await.is_ok_and(|m| m.is_dir()) => {}
+                Err(e) => return Err(TransferError::Disk(e)),
+            }
+            fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).await.map_err(TransferError::Disk)?;
+            dirs.insert(entry.path.clone());
+        } else if fs::symlink_metadata(&path).await.is_ok_and(|metadata| !metadata.is_file()) {
+            return Err(TransferError::Protocol(format!("{} exists and is not a file", path.display())));
+        }
+        targets.push(path);
+    }
+
+    let mut resumed = 0;
+    for (entry, path) in entries.iter().zip(&targets).filter(|(entry, _)| !entry.is_dir) {
+        resumed += receive_body(stream, path, entry.size, chunk_size, pb).await?.resumed_from;
+        restore_metadata(path, entry).map_err(TransferError::Disk)?;
+    }
+    // Deepest first, as filling a directory changes its modification time.
+    for (entry, path) in entries.iter().zip(&targets).rev().filter(|(entry, _)| entry.is_dir) {
+        restore_metadata(path, entry).map_err(TransferError::Disk)?;
+    }
+    write_ok(stream, &[]).await?;
+
+    Ok(TreeReceived { resumed })
+}
+
+pub async fn receive_dir(stream: &mut TcpStream, output: &Path, chunk_size: usize) -> Result<(), TransferError> {
+    let manifest = read_manifest(stream).await?;
+    println!("Receiving directory: {} ({} files, {})", manifest.root(), manifest.files(), HumanBytes(manifest.total()));
+
+    let pb = progress_bar(manifest.total());
+    let received = receive_tree(stream, output, &manifest, chunk_size, &pb).await?;
+
+    pb.finish_with_message("Transfer completed");
+    if received.resumed > 0 {
+        println!("Resumed after {} bytes already in {}", received.resumed, output.display());
+    }
+    println!("Received {} files in {} directories into {}", manifest.files(), manifest.dirs(), output.display());
+    println!("Transfer successful!");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, is_dir: bool) -> Entry {
+        Entry { path: path.to_string(), is_dir, size: 0, mode: 0o644, mtime: 0 }
+    }
+
+    fn dirs(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn targets_stay_under_the_output() {
+        let output = Path::new("/out");
+        let known = dirs(&["d", "d/sub"]);
+        assert_eq!(target(output, "d", &known).unwrap(), Path::new("/out/d"));
+        assert_eq!(target(output, "d/sub/f", &known).unwrap(), Path::new("/out/d/sub/f"));
+        for path in ["", "..", "d/../../etc", "d/./f", "/etc/passwd", "/d/f"] {
+            assert!(matches!(target(output, path, &known), Err(TransferError::Protocol(_))), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn children_must_follow_their_directory() {
+        let output = Path::new("/out");
+        assert!(matches!(target(output, "d/sub/f", &dirs(&["d"])), Err(TransferError::Protocol(_))));
+        assert!(matches!(target(output, "d/f", &dirs(&[])), Err(TransferError::Protocol(_))));
+    }
+
+    #[test]
+    fn manifests_start_with_a_plainly_named_directory() {
+        let manifest = check_manifest(vec![entry("d", true), entry("d/sub", true), entry("d/sub/f", false)]).unwrap();
+        assert_eq!(manifest.root(), "d");
+
+        assert!(check_manifest(vec![]).is_err());
+        assert!(check_manifest(vec![entry("f", false)]).is_err());
+        for root in ["..", ".", "/", "/d", "a/b", ""] {
+            assert!(matches!(check_manifest(vec![entry(root, true)]), Err(TransferError::Protocol(_))), "{:?}", root);
+        }
+    }
+
+    #[test]
+    fn manifests_list_nothing_outside_the_root() {
+        for path in ["e", "e/f", "dd/f", "d", "/d/f"] {
+            let entries = vec![entry("d", true), entry(path, false)];
+            assert!(matches!(check_manifest(entries), Err(TransferError::Protocol(_))), "{:?}", path);
+        }
+    }
+}
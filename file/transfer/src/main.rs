@@ -1,19 +1,26 @@
+mod dir;
+mod server;
+
 use clap::{Parser, Subcommand};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use sha2::{Sha256, Digest};
-use std::path::PathBuf;
+use std::convert::identity;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt, Error as IoError},
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, Error as IoError, ErrorKind, SeekFrom},
     net::{TcpListener, TcpStream},
-    time::{timeout, Duration, error::Elapsed},
+    sync::mpsc,
+    time::{timeout, Duration, Instant, error::Elapsed},
 };
 
 #[derive(Error, Debug)]
 pub enum TransferError {
     #[error("IO error: {0}")]
     Io(#[from] IoError),
+    #[error("Disk error: {0}")]
+    Disk(IoError),
     #[error("Connection timeout")]
     Timeout(#[from] Elapsed),
     #[error("Transfer incomplete: expected {expected} bytes, received {received} bytes")]
@@ -21,6 +28,27 @@ pub enum TransferError {
         expected: u64,
         received: u64,
     },
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Refused: {0}")]
+    Refused(String),
+    #[error("Integrity check failed for {file}: sender's hash {expected}, received data hashes to {actual}")]
+    Integrity {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Receiver failed: {0}")]
+    Remote(String),
+}
+
+// Files are read from and written to disk on the receiving side, so IO errors
+// there are the receiver's own problem rather than the connection's.
+fn on_disk(error: TransferError) -> TransferError {
+    match error {
+        TransferError::Io(e) => TransferError::Disk(e),
+        e => e,
+    }
 }
 
 #[derive(Parser)]
@@ -33,6 +61,7 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Send {
+        // A file, or a directory to send with everything in it
         #[arg(short, long)]
         file: PathBuf,
 
@@ -41,18 +70,87 @@ enum Commands {
 
         #[arg(short, long, default_value_t = 8080)]
         port: u16,
+
+        // Bytes per read and write, e.g. 64K or 1M
+        #[arg(long, default_value = "64K", value_parser = parse_chunk_size)]
+        chunk_size: usize,
     },
     Receive {
+        // Where a file is written, or where a directory is created
         #[arg(short, long)]
         output: PathBuf,
 
         #[arg(short, long, default_value_t = 8080)]
         port: u16,
+
+        #[arg(long, default_value = "64K", value_parser = parse_chunk_size)]
+        chunk_size: usize,
+    },
+    // Keeps accepting transfers, several at a time, into a directory
+    Serve {
+        // Files are written here under the name the sender gave them
+        #[arg(short, long)]
+        dir: PathBuf,
+
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        #[arg(long, default_value = "64K", value_parser = parse_chunk_size)]
+        chunk_size: usize,
+    },
+    // Sends a generated file to ourselves over loopback with each chunk size
+    Bench {
+        #[arg(long, default_value = "256M", value_parser = parse_size)]
+        size: u64,
+
+        // May be repeated; defaults to 4K, 16K, 64K, 256K and 1M
+        #[arg(long = "chunk-size", value_parser = parse_chunk_size)]
+        chunk_sizes: Vec<usize>,
     },
 }
 
-const BUFFER_SIZE: usize = 15; // TOBE_CHANGED
 const TIMEOUT_SECS: u64 = 30;
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+// One buffer is filled while the other is written out.
+const BUFFERS: usize = 2;
+// The first byte of a transfer says what follows.
+const KIND_FILE: u8 = 0;
+const KIND_DIR: u8 = 1;
+// Everything the receiver sends starts with one of these. Anything but OK is
+// followed by a string saying what went wrong, and is the last thing sent.
+const STATUS_OK: u8 = 0;
+const STATUS_HASH_MISMATCH: u8 = 1;
+const STATUS_DISK_ERROR: u8 = 2;
+const STATUS_REFUSED: u8 = 3;
+
+// Parses sizes like 4096, 64K, 1M or 2G (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown unit {:?}", unit)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("{:?} is not a size", s))
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    match parse_size(s)? {
+        0 => Err("chunk size must be at least 1 byte".to_string()),
+        n if n > MAX_CHUNK_SIZE => Err(format!("chunk size must be at most {}", HumanBytes(MAX_CHUNK_SIZE))),
+        n => Ok(n as usize),
+    }
+}
 
 async fn handle_timeout<T, E>(
     future: impl std::future::Future<Output = Result<T, E>>,
@@ -98,60 +196,233 @@ async fn read_string_from_stream(stream: &mut TcpStream) -> Result<String, Trans
     Ok(String::from_utf8_lossy(&string_buf).into_owned())
 }
 
+// Copies `len` bytes from `reader` to `writer`, hashing them on the way.
+// Reading the next chunk overlaps with writing the previous one, so disk and
+// network are busy at the same time. Returns how many bytes were copied,
+// which is short of `len` only if the reader ran out. Errors writing go
+// through `writer_error`.
+async fn pipe<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    chunk_size: usize,
+    hasher: &mut Sha256,
+    pb: &ProgressBar,
+    writer_error: fn(TransferError) -> TransferError,
+) -> Result<u64, TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (full_tx, mut full_rx) = mpsc::channel::<Vec<u8>>(BUFFERS);
+    let (empty_tx, mut empty_rx) = mpsc::channel::<Vec<u8>>(BUFFERS);
+    for _ in 0..BUFFERS {
+        empty_tx.try_send(vec![0; chunk_size]).unwrap();
+    }
+
+    let read = async move {
+        let mut copied = 0;
+        while copied < len {
+            let Some(mut buffer) = empty_rx.recv().await else { break };
+            let want = (len - copied).min(chunk_size as u64) as usize;
+            buffer.resize(want, 0);
+            let n = handle_timeout(reader.read(&mut buffer)).await?;
+            if n == 0 { break; }
+            buffer.truncate(n);
+            copied += n as u64;
+            if full_tx.send(buffer).await.is_err() { break; }
+        }
+        Ok::<u64, TransferError>(copied)
+    };
+
+    let write = async move {
+        while let Some(buffer) = full_rx.recv().await {
+            handle_timeout(writer.write_all(&buffer)).await.map_err(writer_error)?;
+            hasher.update(&buffer);
+            pb.inc(buffer.len() as u64);
+            // The reader may already be done with it.
+            let _ = empty_tx.send(buffer).await;
+        }
+        handle_timeout(writer.flush()).await.map_err(writer_error)?;
+        Ok::<(), TransferError>(())
+    };
+
+    let (copied, ()) = tokio::try_join!(read, write)?;
+    Ok(copied)
+}
+
+// Sends `STATUS_OK` and `payload` in one go.
+async fn write_ok(stream: &mut TcpStream, payload: &[u8]) -> Result<(), TransferError> {
+    let mut reply = vec![STATUS_OK];
+    reply.extend_from_slice(payload);
+    handle_timeout(stream.write_all(&reply)).await?;
+    handle_timeout(stream.flush()).await?;
+    Ok(())
+}
+
+// Reads the receiver's status, turning a failure into the matching error.
+// `file` is what we're sending, for the error message.
+async fn read_status(stream: &mut TcpStream, file: &str) -> Result<(), TransferError> {
+    let status = handle_timeout(stream.read_u8()).await?;
+    if status == STATUS_OK {
+        return Ok(());
+    }
+    let message = read_string_from_stream(stream).await?;
+    Err(match status {
+        STATUS_HASH_MISMATCH => {
+            let (expected, actual) = message.split_once(' ').unwrap_or((&message, ""));
+            TransferError::Integrity {
+                file: file.to_string(),
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            }
+        }
+        STATUS_DISK_ERROR => TransferError::Remote(format!("disk error: {}", message)),
+        STATUS_REFUSED => TransferError::Refused(message),
+        status => TransferError::Protocol(format!("unknown status {}: {}", status, message)),
+    })
+}
+
+// Tells the sender why we're giving up, when it's something it can't tell by
+// itself, then reads whatever it still sends until it has read the status
+// and hung up.
+async fn report_failure(stream: &mut TcpStream, error: &TransferError) {
+    let (status, message) = match error {
+        TransferError::Integrity { expected, actual, .. } => (STATUS_HASH_MISMATCH, format!("{} {}", expected, actual)),
+        TransferError::Disk(e) => (STATUS_DISK_ERROR, e.to_string()),
+        TransferError::Refused(message) | TransferError::Protocol(message) => (STATUS_REFUSED, message.clone()),
+        _ => return,
+    };
+    if handle_timeout(stream.write_u8(status)).await.is_err()
+        || write_string_to_stream(stream, &message).await.is_err()
+    {
+        return;
+    }
+    let mut buffer = vec![0; 64 * 1024];
+    while let Ok(n) = handle_timeout(stream.read(&mut buffer)).await {
+        if n == 0 { break; }
+    }
+}
+
+fn progress_bar(len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb
+}
+
 #[tokio::main]
 async fn main() -> Result<(), TransferError> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Send { file, host, port } => {
-            send_file(file, &host, port).await?;
+        Commands::Send { file, host, port, chunk_size } => {
+            send_file(file, &host, port, chunk_size).await?;
+        }
+        Commands::Receive { output, port, chunk_size } => {
+            receive_file(output, port, chunk_size).await?;
         }
-        Commands::Receive { output, port } => {
-            receive_file(output, port).await?;
+        Commands::Serve { dir, port, chunk_size } => {
+            server::serve(dir, port, chunk_size).await?;
+        }
+        Commands::Bench { size, chunk_sizes } => {
+            bench(size, chunk_sizes).await?;
         }
     }
 
     Ok(())
 }
 
-async fn send_file(file_path: PathBuf, host: &str, port: u16) -> Result<(), TransferError> {
+struct Sent {
+    resumed_from: u64,
+    hash: [u8; 32],
+}
+
+struct Received {
+    resumed_from: u64,
+    hash: [u8; 32],
+}
+
+async fn send_file(file_path: PathBuf, host: &str, port: u16, chunk_size: usize) -> Result<(), TransferError> {
+    if tokio::fs::metadata(&file_path).await?.is_dir() {
+        let mut stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        println!("Connected to receiver");
+        stream.set_nodelay(true)?;
+        return dir::send_dir(&mut stream, &file_path, chunk_size).await;
+    }
+
     let mut file = File::open(&file_path).await?;
     let file_size = file.metadata().await?.len();
 
     let mut stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
     println!("Connected to receiver");
-
     stream.set_nodelay(true)?;
 
-    write_u64_to_stream(&mut stream, file_size).await?;
+    let file_name = write_file_header(&mut stream, &file_path, file_size).await?;
+    let pb = progress_bar(file_size);
+    let sent = send_body(&mut stream, &mut file, &file_name, file_size, chunk_size, &pb).await?;
+
+    pb.finish_with_message("Transfer completed");
+    if sent.resumed_from > 0 {
+        println!("Resumed after {} bytes the receiver already had", sent.resumed_from);
+    }
+    println!("File hash: {}", hex::encode(sent.hash));
+    println!("Receiver verified the file");
+
+    Ok(())
+}
+
+// Returns the file name sent.
+async fn write_file_header(stream: &mut TcpStream, file_path: &Path, file_size: u64) -> Result<String, TransferError> {
+    handle_timeout(stream.write_u8(KIND_FILE)).await?;
+    write_u64_to_stream(stream, file_size).await?;
 
     let file_name = file_path.file_name()
         .unwrap_or_default()
         .to_string_lossy()
         .into_owned();
-    write_string_to_stream(&mut stream, &file_name).await?;
-
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
+    write_string_to_stream(stream, &file_name).await?;
+    Ok(file_name)
+}
 
+// Sends one file's contents and hash, after its header, and waits for the
+// receiver to confirm it has the file.
+async fn send_body(
+    stream: &mut TcpStream,
+    file: &mut File,
+    name: &str,
+    file_size: u64,
+    chunk_size: usize,
+    pb: &ProgressBar,
+) -> Result<Sent, TransferError> {
+    // The receiver says how much of the file it already has and sends the
+    // hash of that part. We hash our own first bytes meanwhile and continue
+    // from there if they match, so the hasher then covers the whole file.
+    read_status(stream, name).await?;
+    let offered = read_u64_from_stream(stream).await?;
     let mut hasher = Sha256::new();
-    let mut buffer = vec![0; BUFFER_SIZE];
-    let mut sent = 0;
-
-    while sent < file_size {
-        let n = handle_timeout(file.read(&mut buffer)).await?;
-        if n == 0 { break; }
-    
-        handle_timeout(stream.write_all(&buffer[..n])).await?;
-        handle_timeout(stream.flush()).await?;
-    
-        hasher.update(&buffer[..n]);
-        sent += n as u64;
-        pb.set_position(sent);
+    let mut prefix = 0;
+    if offered <= file_size {
+        let sink = &mut tokio::io::sink();
+        prefix = pipe(file, sink, offered, chunk_size, &mut hasher, &ProgressBar::hidden(), identity).await?;
     }
+    read_status(stream, name).await?;
+    let mut their_hash = [0u8; 32];
+    handle_timeout(stream.read_exact(&mut their_hash)).await?;
+
+    let resumed_from = if prefix == offered && hasher.clone().finalize()[..] == their_hash {
+        offered
+    } else {
+        file.seek(SeekFrom::Start(0)).await?;
+        hasher = Sha256::new();
+        0
+    };
+    write_u64_to_stream(stream, resumed_from).await?;
+
+    pb.inc(resumed_from);
+    let sent = resumed_from + pipe(file, stream, file_size - resumed_from, chunk_size, &mut hasher, pb, identity).await?;
 
     if sent != file_size {
         return Err(TransferError::Incomplete {
@@ -160,17 +431,15 @@ async fn send_file(file_path: PathBuf, host: &str, port: u16) -> Result<(), Tran
         });
     }
 
-    let hash = hasher.finalize();
+    let hash: [u8; 32] = hasher.finalize().into();
     handle_timeout(stream.write_all(&hash)).await?;
     handle_timeout(stream.flush()).await?;
+    read_status(stream, name).await?;
 
-    pb.finish_with_message("Transfer completed");
-    println!("File hash: {}", hex::encode(hash));
-
-    Ok(())
+    Ok(Sent { resumed_from, hash })
 }
 
-async fn receive_file(output_path: PathBuf, port: u16) -> Result<(), TransferError> {
+async fn receive_file(output_path: PathBuf, port: u16, chunk_size: usize) -> Result<(), TransferError> {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
     println!("Listening on port {}", port);
 
@@ -179,59 +448,239 @@ async fn receive_file(output_path: PathBuf, port: u16) -> Result<(), TransferErr
 
     stream.set_nodelay(true)?;
 
-    let file_size = read_u64_from_stream(&mut stream).await?;
+    let result = match handle_timeout(stream.read_u8()).await? {
+        KIND_FILE => receive_single(&mut stream, &output_path, chunk_size).await,
+        KIND_DIR => dir::receive_dir(&mut stream, &output_path, chunk_size).await,
+        kind => Err(TransferError::Protocol(format!("unknown transfer kind {}", kind))),
+    };
+    if let Err(e) = &result {
+        report_failure(&mut stream, e).await;
+    }
+    result
+}
+
+async fn receive_single(stream: &mut TcpStream, output_path: &Path, chunk_size: usize) -> Result<(), TransferError> {
+    let file_size = read_u64_from_stream(stream).await?;
 
-    let file_name = read_string_from_stream(&mut stream).await?;
+    let file_name = read_string_from_stream(stream).await?;
     println!("Receiving file: {} ({} bytes)", file_name, file_size);
 
-    let mut file = File::create(&output_path).await?;
+    let pb = progress_bar(file_size);
+    let received = receive_body(stream, output_path, file_size, chunk_size, &pb).await?;
 
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
+    pb.finish_with_message("Transfer completed");
+    if received.resumed_from > 0 {
+        println!("Resumed after {} bytes from an earlier attempt", received.resumed_from);
+    }
+
+    println!("File hash verified: {}", hex::encode(received.hash));
+    println!("Transfer successful!");
+
+    Ok(())
+}
+
+// Where a file is written until its hash checks out. It is left behind if
+// the transfer is cut off, for the next attempt to resume from.
+fn partial_path(output_path: &Path) -> PathBuf {
+    let name = output_path.file_name().unwrap_or_default().to_string_lossy();
+    output_path.with_file_name(format!(".{}.part", name))
+}
+
+// Receives one file's contents and hash. Only once the hash matches does the
+// file appear at `output_path`; until then it is written to its partial
+// path, continuing what an earlier attempt left there if the sender agrees
+// it is a prefix. Failures are left to the caller to report.
+async fn receive_body(
+    stream: &mut TcpStream,
+    output_path: &Path,
+    file_size: u64,
+    chunk_size: usize,
+    pb: &ProgressBar,
+) -> Result<Received, TransferError> {
+    let partial = partial_path(output_path);
+    if tokio::fs::symlink_metadata(&partial).await.is_ok_and(|metadata| metadata.is_symlink()) {
+        return Err(TransferError::Refused(format!("{} is a symlink", partial.display())));
+    }
+    let (mut file, offered) = match OpenOptions::new().read(true).write(true).open(&partial).await {
+        Ok(file) => {
+            let len = file.metadata().await.map_err(TransferError::Disk)?.len();
+            (file, if len <= file_size { len } else { 0 })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => (File::create(&partial).await.map_err(TransferError::Disk)?, 0),
+        Err(e) => return Err(TransferError::Disk(e)),
+    };
+    write_ok(stream, &offered.to_be_bytes()).await?;
 
     let mut hasher = Sha256::new();
-    let mut buffer = vec![0; BUFFER_SIZE];
-    let mut received = 0;
-
-    tokio::time::sleep(Duration::from_millis(100)).await;
-
-    while received < file_size {
-        let n = match handle_timeout(stream.read(&mut buffer)).await? {
-            0 => {
-                return Err(TransferError::Incomplete {
-                    expected: file_size,
-                    received,
-                });
-            }
-            n => n,
-        };
+    let sink = &mut tokio::io::sink();
+    let prefix = pipe(&mut file, sink, offered, chunk_size, &mut hasher, &ProgressBar::hidden(), identity)
+        .await
+        .map_err(on_disk)?;
+    write_ok(stream, &hasher.clone().finalize()).await?;
+
+    let resumed_from = read_u64_from_stream(stream).await?;
+    if resumed_from == 0 {
+        file.set_len(0).await.map_err(TransferError::Disk)?;
+        file.seek(SeekFrom::Start(0)).await.map_err(TransferError::Disk)?;
+        hasher = Sha256::new();
+    } else if resumed_from != prefix {
+        return Err(TransferError::Protocol(format!(
+            "sender resumed at {} bytes, {} were offered",
+            resumed_from, prefix
+        )));
+    }
 
-        handle_timeout(file.write_all(&buffer[..n])).await?;
-        file.flush().await?;
+    pb.inc(resumed_from);
+    let received = resumed_from + pipe(stream, &mut file, file_size - resumed_from, chunk_size, &mut hasher, pb, on_disk).await?;
 
-        hasher.update(&buffer[..n]);
-        received += n as u64;
-        pb.set_position(received);
+    if received != file_size {
+        return Err(TransferError::Incomplete {
+            expected: file_size,
+            received,
+        });
     }
 
     let mut received_hash = [0u8; 32];
     handle_timeout(stream.read_exact(&mut received_hash)).await?;
 
-    let calculated_hash = hasher.finalize();
+    let hash: [u8; 32] = hasher.finalize().into();
+    if hash != received_hash {
+        drop(file);
+        // Resuming from a corrupt file would only fail again.
+        tokio::fs::remove_file(&partial).await.map_err(TransferError::Disk)?;
+        return Err(TransferError::Integrity {
+            file: output_path.display().to_string(),
+            expected: hex::encode(received_hash),
+            actual: hex::encode(hash),
+        });
+    }
 
-    pb.finish_with_message("Transfer completed");
+    file.sync_all().await.map_err(TransferError::Disk)?;
+    drop(file);
+    tokio::fs::rename(&partial, output_path).await.map_err(TransferError::Disk)?;
+    write_ok(stream, &[]).await?;
 
-    if received_hash == calculated_hash[..] {
-        println!("File hash verified: {}", hex::encode(calculated_hash));
-        println!("Transfer successful!");
-    } else {
-        println!("Warning: File hash mismatch!");
-        println!("Received:    {}", hex::encode(received_hash));
-        println!("Calculated:  {}", hex::encode(calculated_hash));
+    Ok(Received { resumed_from, hash })
+}
+
+// Times a transfer of a generated file over loopback for each chunk size.
+async fn bench(size: u64, mut chunk_sizes: Vec<usize>) -> Result<(), TransferError> {
+    if chunk_sizes.is_empty() {
+        chunk_sizes = vec![4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+    }
+
+    let dir = std::env::temp_dir().join(format!("transfer-bench-{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await?;
+    let input = dir.join("input.bin");
+    let output = dir.join("output.bin");
+    let result = run_bench(&input, &output, size, &chunk_sizes).await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    result
+}
+
+async fn run_bench(input: &Path, output: &Path, size: u64, chunk_sizes: &[usize]) -> Result<(), TransferError> {
+    println!("Writing {} test file", HumanBytes(size));
+    write_test_file(input, size).await?;
+
+    println!("{:>12}  {:>8}  {:>12}", "chunk size", "time", "throughput");
+    for &chunk_size in chunk_sizes {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let receiver = async {
+            let (mut stream, _) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            handle_timeout(stream.read_u8()).await?;
+            let file_size = read_u64_from_stream(&mut stream).await?;
+            read_string_from_stream(&mut stream).await?;
+            let result = receive_body(&mut stream, output, file_size, chunk_size, &ProgressBar::hidden()).await;
+            if let Err(e) = &result {
+                report_failure(&mut stream, e).await;
+            }
+            result
+        };
+        let sender = async {
+            let mut file = File::open(input).await?;
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            let name = write_file_header(&mut stream, input, size).await?;
+            send_body(&mut stream, &mut file, &name, size, chunk_size, &ProgressBar::hidden()).await
+        };
+
+        let started = Instant::now();
+        tokio::try_join!(receiver, sender)?;
+        let elapsed = started.elapsed();
+
+        let mb_per_sec = size as f64 / 1_000_000.0 / elapsed.as_secs_f64();
+        println!(
+            "{:>12}  {:>7.2}s  {:>7.1} MB/s",
+            HumanBytes(chunk_size as u64).to_string(),
+            elapsed.as_secs_f64(),
+            mb_per_sec
+        );
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Fills `path` with `size` bytes of cheap pseudo-random data.
+async fn write_test_file(path: &Path, size: u64) -> Result<(), TransferError> {
+    let mut file = File::create(path).await?;
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut written = 0;
+    while written < size {
+        for word in buffer.chunks_mut(8) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes());
+        }
+        let n = (size - written).min(buffer.len() as u64) as usize;
+        file.write_all(&buffer[..n]).await?;
+        written += n as u64;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_take_binary_unit_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("10B"), Ok(10));
+        for unit in ["K", "k", "KB", "KiB"] {
+            assert_eq!(parse_size(&format!("64{}", unit)), Ok(64 * 1024));
+        }
+        for unit in ["M", "MB", "MiB"] {
+            assert_eq!(parse_size(&format!("3{}", unit)), Ok(3 * 1024 * 1024));
+        }
+        for unit in ["G", "GB", "GiB"] {
+            assert_eq!(parse_size(&format!("2{}", unit)), Ok(2 * 1024 * 1024 * 1024));
+        }
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn malformed_and_overflowing_sizes_are_rejected() {
+        assert!(parse_size("12T").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("17179869184G").is_err());
+    }
+
+    #[test]
+    fn chunk_sizes_are_between_one_byte_and_64m() {
+        assert_eq!(parse_chunk_size("1"), Ok(1));
+        assert_eq!(parse_chunk_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_chunk_size("64M"), Ok(64 * 1024 * 1024));
+        assert!(parse_chunk_size("0").is_err());
+        assert!(parse_chunk_size("0K").is_err());
+        assert!(parse_chunk_size("65537K").is_err());
+        assert!(parse_chunk_size("1G").is_err());
+    }
+}
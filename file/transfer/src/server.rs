@@ -0,0 +1,152 @@
+use crate::{
+    dir, handle_timeout, read_string_from_stream, read_u64_from_stream, receive_body, report_failure,
+    TransferError, KIND_DIR, KIND_FILE,
+};
+use indicatif::{HumanBytes, ProgressBar};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    fs,
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+// Names currently being written, so two senders can't write the same file
+// or directory at once.
+type Busy = Arc<Mutex<HashSet<String>>>;
+
+// Holds a name in `Busy` until dropped.
+struct Claim {
+    busy: Busy,
+    name: String,
+}
+
+impl Claim {
+    fn new(busy: &Busy, name: &str) -> Result<Self, TransferError> {
+        if !busy.lock().unwrap().insert(name.to_string()) {
+            return Err(TransferError::Refused(format!("{} is already being received", name)));
+        }
+        Ok(Claim { busy: Arc::clone(busy), name: name.to_string() })
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.name);
+    }
+}
+
+// Keeps only the last component of what the sender called its file, so it
+// can't name anything outside our directory.
+fn sanitize(file_name: &str) -> Result<&str, TransferError> {
+    Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| TransferError::Refused(format!("unusable file name {:?}", file_name)))
+}
+
+pub async fn serve(dir: PathBuf, port: u16, chunk_size: usize) -> Result<(), TransferError> {
+    fs::create_dir_all(&dir).await?;
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    println!("Receiving into {} on port {}", dir.display(), port);
+
+    let busy = Busy::default();
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let dir = dir.clone();
+        let busy = Arc::clone(&busy);
+        tokio::spawn(async move {
+            if let Err(e) = receive(&mut stream, addr, &dir, chunk_size, &busy).await {
+                println!("{}: failed: {}", addr, e);
+                report_failure(&mut stream, &e).await;
+            }
+        });
+    }
+}
+
+// Receives one transfer, logging when it starts and how it ended.
+async fn receive(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    dir: &Path,
+    chunk_size: usize,
+    busy: &Busy,
+) -> Result<(), TransferError> {
+    stream.set_nodelay(true)?;
+    let started = Instant::now();
+
+    match handle_timeout(stream.read_u8()).await? {
+        KIND_FILE => {
+            let file_size = read_u64_from_stream(stream).await?;
+            let file_name = read_string_from_stream(stream).await?;
+            let name = sanitize(&file_name)?;
+            let _claim = Claim::new(busy, name)?;
+            let path = dir.join(name);
+            if fs::symlink_metadata(&path).await.is_ok_and(|metadata| metadata.is_symlink()) {
+                return Err(TransferError::Refused(format!("{} is a symlink", path.display())));
+            }
+
+            println!("{}: receiving {} ({})", addr, name, HumanBytes(file_size));
+            receive_body(stream, &path, file_size, chunk_size, &ProgressBar::hidden()).await?;
+            let elapsed = started.elapsed().as_secs_f64();
+            println!("{}: received {} in {:.1}s, hash verified", addr, name, elapsed);
+        }
+        KIND_DIR => {
+            // The manifest is checked to list nothing outside its root before
+            // anything is claimed, so claiming the root covers the whole tree.
+            let manifest = dir::read_manifest(stream).await?;
+            let _claim = Claim::new(busy, manifest.root())?;
+
+            println!(
+                "{}: receiving directory {} ({} files, {})",
+                addr,
+                manifest.root(),
+                manifest.files(),
+                HumanBytes(manifest.total())
+            );
+            dir::receive_tree(stream, dir, &manifest, chunk_size, &ProgressBar::hidden()).await?;
+            let elapsed = started.elapsed().as_secs_f64();
+            println!("{}: received directory {} in {:.1}s, hashes verified", addr, manifest.root(), elapsed);
+        }
+        kind => return Err(TransferError::Protocol(format!("unknown transfer kind {}", kind))),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_only_the_file_name() {
+        assert_eq!(sanitize("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sanitize("../x").unwrap(), "x");
+        assert_eq!(sanitize("/a/../b").unwrap(), "b");
+        assert_eq!(sanitize("/etc/passwd").unwrap(), "passwd");
+        for name in ["..", "a/..", "/", ""] {
+            assert!(matches!(sanitize(name), Err(TransferError::Refused(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn a_name_is_claimed_once_at_a_time() {
+        let busy = Busy::default();
+        let claim = Claim::new(&busy, "f").unwrap();
+        assert!(matches!(Claim::new(&busy, "f"), Err(TransferError::Refused(_))));
+        let other = Claim::new(&busy, "g").unwrap();
+        drop(claim);
+        assert!(Claim::new(&busy, "f").is_ok());
+        drop(other);
+        assert!(busy.lock().unwrap().is_empty());
+    }
+}